@@ -0,0 +1,50 @@
+//在本地的 SIFT/GIST 数据集上测试 构建时间 磁盘大小 QPS 和 recall@k
+//<db> 必须是一个还不存在的目录
+//cargo run --release --example bench -- <db> <base.fvecs|bvecs> <query.fvecs|bvecs> <groundtruth.ivecs> [--limit n] [--k 10] [--ef 16,32,64,128] [--nb-conn 16] [--ef-construction 200]
+use anyhow::{anyhow, Result};
+use arrowdb::db::{dataset, ArrowDB, CollectionOptions};
+use std::path::Path;
+use std::time::Instant;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 4 {
+        return Err(anyhow!("usage: bench <db> <base> <query> <groundtruth> [--limit n] [--k k] [--ef ef1,ef2,...] [--nb-conn m] [--ef-construction ef]"));
+    }
+    let (mut limit, mut k, mut efs, mut nb_conn, mut ef_construction) = (None, 10, vec![16, 32, 64, 128, 256], 16, 200);
+    let mut options = args[4..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(anyhow!("{} need a value", option))?;
+        match option.as_str() {
+            "--limit" => limit = Some(value.parse()?),
+            "--k" => k = value.parse()?,
+            "--ef" => efs = value.split(',').map(|v| v.parse()).collect::<Result<_, _>>()?,
+            "--nb-conn" => nb_conn = value.parse()?,
+            "--ef-construction" => ef_construction = value.parse()?,
+            _ => return Err(anyhow!("unknown option {}", option)),
+        }
+    }
+
+    let dimension = dataset::read_vectors(&args[1], Some(1))?.first().map(|v| v.len()).ok_or(anyhow!("{} is empty", args[1]))?;
+    if Path::new(&args[0]).exists() {                  //不删除已有的数据库 需要一个新的目录
+        return Err(anyhow!("{} already exists, remove it or choose another <db> path", args[0]));
+    }
+    let arrow_db = ArrowDB::new(&args[0]);
+    arrow_db.create_collection_with("bench", CollectionOptions::new(dimension).nb_conn(nb_conn).ef(ef_construction))?;
+    let hnsw = arrow_db.get_hnsw("bench", dimension)?;
+
+    let start = Instant::now();
+    let ids = dataset::load(&hnsw, &args[1], limit, 10000)?;
+    println!("build {} vectors of dimension {} in {:?}", ids.len(), dimension, start.elapsed());
+    println!("index size {:.1} MB", dataset::disk_size(&args[0])? as f64 / (1024. * 1024.));
+
+    let queries = dataset::read_vectors(&args[2], None)?;
+    let truth = dataset::read_ivecs(&args[3], Some(queries.len()))?;
+    if limit.is_some() {
+        println!("warning: ground truth was computed on the full base set, recall is not meaningful with --limit");
+    }
+    for ef in efs {
+        println!("{}", dataset::benchmark(&hnsw, &ids, &queries, &truth, k, ef)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+//评估一个已有集合的召回率
+//cargo run --release --example eval -- <db> <collection> [--queries file | --samples 100] [--k 10] [--ef 16,32,64,128]
+use anyhow::{anyhow, Result};
+use arrowdb::db::{eval, ArrowDB};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        return Err(anyhow!("usage: eval <db> <collection> [--queries file | --samples n] [--k k] [--ef ef1,ef2,...]"));
+    }
+    let (mut queries, mut samples, mut k, mut efs) = (None, 100, 10, vec![16, 32, 64, 128, 256]);
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(anyhow!("{} need a value", option))?;
+        match option.as_str() {
+            "--queries" => queries = Some(value.clone()),
+            "--samples" => samples = value.parse()?,
+            "--k" => k = value.parse()?,
+            "--ef" => efs = value.split(',').map(|v| v.parse()).collect::<Result<_, _>>()?,
+            _ => return Err(anyhow!("unknown option {}", option)),
+        }
+    }
+
+    let arrow_db = ArrowDB::new(&args[0]);
+    let collection = arrow_db.get_collection(&args[1]).ok_or(anyhow!("collection {} do not existed", args[1]))?;
+    let hnsw = arrow_db.get_hnsw(&args[1], collection.dimension())?;
+    let queries = match queries {
+        Some(path) => eval::load_queries(&path)?,
+        None => eval::sample_queries(&hnsw, samples)?,
+    };
+    println!("{} vectors, {} queries", hnsw.len(), queries.len());
+    for report in eval::evaluate(&hnsw, &queries, k, &efs)? {
+        println!("{}", report);
+    }
+    Ok(())
+}
@@ -7,9 +7,9 @@ use rayon::prelude::*;
 fn main() -> Result<()> {
     let arrow_db = ArrowDB::new("arrow_db");
     let dim = 1024;
-    arrow_db.create_collection("test1", 128).unwrap();
+    let _ = arrow_db.create_collection("test1", 128);              //已经存在时返回错误
     let a_db = arrow_db.clone();
-    a_db.create_collection("test2", dim).unwrap();
+    let _ = a_db.create_collection("test2", dim);
 
     println!("{:?}", arrow_db.get_collections());
     let nb_elem = 1024;
@@ -21,13 +21,11 @@ fn main() -> Result<()> {
         data.push(column);
     }
 
-    let data_with_id = data.iter().zip(0..data.len()).collect::<Vec<_>>();
-
     let hns = arrow_db.get_hnsw("test2", dim)?;
-    let _ids: Vec<u64> = data_with_id.par_iter().map(|&data|
-        hns.insert(data.0.clone()).unwrap()
+    let _ids: Vec<u64> = data.par_iter().map(|data|
+        hns.insert(data.clone()).unwrap()
     ).collect();
 
-    println!("{:?}", hns.search(data_with_id[100].0.clone(), 10));
+    println!("{:?}", hns.search(data[100].clone(), 10));
     Ok(())
 }
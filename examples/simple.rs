@@ -6,9 +6,9 @@ use rand::prelude::*;
 fn main() -> Result<()> {
     let arrow_db = ArrowDB::new("arrow_db");
     let dim = 1024;
-    arrow_db.create_collection("test1", 128).unwrap();
+    let _ = arrow_db.create_collection("test1", 128);              //已经存在时返回错误
     let a_db = arrow_db.clone();
-    a_db.create_collection("test2", dim).unwrap();
+    let _ = a_db.create_collection("test2", dim);
 
     println!("{:?}", arrow_db.get_collections());
     let nb_elem = 1024;
@@ -20,12 +20,10 @@ fn main() -> Result<()> {
         data.push(column);
     }
 
-    let data_with_id = data.iter().zip(0..data.len()).collect::<Vec<_>>();  // give an id to each data
-
     let hns = arrow_db.get_hnsw("test2", dim)?;
-    for _i in 0..data_with_id.len() {
-        let _ = hns.insert(data_with_id[_i].0.clone());
+    for arrow in &data {
+        let _ = hns.insert(arrow.clone());
     }
-    println!("{:?}", hns.search(data_with_id[100].0.clone(), 10));
+    println!("{:?}", hns.search(data[100].clone(), 10));
     Ok(())
 }
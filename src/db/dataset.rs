@@ -0,0 +1,213 @@
+//TEXMEX 格式的数据集 (SIFT GIST 等) 每条记录是 4 字节小端的维度 后面跟着 dim 个分量
+//.fvecs 分量是 f32  .ivecs 分量是 i32 (一般是 ground truth)  .bvecs 分量是 u8
+use super::hnsw::HNSW;
+use super::search::SearchParams;
+use crate::store::KVStore;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::time::Instant;
+
+//分量的类型 同样是 4 字节的 f32 和 i32 不能混用
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VecsKind {
+    Float,
+    Int,
+    Byte,
+}
+
+impl VecsKind {
+    fn size(self) -> usize {
+        match self {
+            Self::Float | Self::Int => 4,
+            Self::Byte => 1,
+        }
+    }
+}
+
+pub struct VecsReader<R: Read> {
+    reader: R,
+    kind: VecsKind,
+}
+
+impl VecsReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let kind = match path.extension().and_then(|e| e.to_str()) {
+            Some("fvecs") => VecsKind::Float,
+            Some("ivecs") => VecsKind::Int,
+            Some("bvecs") => VecsKind::Byte,
+            _ => return Err(anyhow!("{} is not a fvecs/ivecs/bvecs file", path.display())),
+        };
+        Ok(Self::new(BufReader::new(File::open(path)?), kind))
+    }
+}
+
+impl<R: Read> VecsReader<R> {
+    pub fn new(reader: R, kind: VecsKind) -> Self {
+        Self { reader, kind }
+    }
+
+    //读一条原始记录 文件结束返回 None
+    fn next_raw(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut dim = [0u8; 4];
+        match self.reader.read_exact(&mut dim) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            r => r?,
+        }
+        let dim = i32::from_le_bytes(dim);
+        if dim <= 0 {
+            return Err(anyhow!("invalid dimension {}", dim));
+        }
+        let mut raw = vec![0u8; dim as usize * self.kind.size()];
+        self.reader.read_exact(&mut raw)?;
+        Ok(Some(raw))
+    }
+
+    //整数分量按数值转换成 f32
+    pub fn next_f32(&mut self) -> Result<Option<Vec<f32>>> {
+        Ok(self.next_raw()?.map(|raw| match self.kind {
+            VecsKind::Byte => raw.into_iter().map(|v| v as f32).collect(),
+            VecsKind::Int => raw.chunks_exact(4).map(|v| i32::from_le_bytes(v.try_into().unwrap()) as f32).collect(),
+            VecsKind::Float => raw.chunks_exact(4).map(|v| f32::from_le_bytes(v.try_into().unwrap())).collect(),
+        }))
+    }
+
+    pub fn next_i32(&mut self) -> Result<Option<Vec<i32>>> {
+        Ok(self.next_raw()?.map(|raw| match self.kind {
+            VecsKind::Byte => raw.into_iter().map(|v| v as i32).collect(),
+            VecsKind::Int => raw.chunks_exact(4).map(|v| i32::from_le_bytes(v.try_into().unwrap())).collect(),
+            VecsKind::Float => raw.chunks_exact(4).map(|v| f32::from_le_bytes(v.try_into().unwrap()) as i32).collect(),
+        }))
+    }
+}
+
+//读取最多 limit 个向量 .bvecs 转换成 f32
+pub fn read_vectors<P: AsRef<Path>>(path: P, limit: Option<usize>) -> Result<Vec<Vec<f32>>> {
+    let mut reader = VecsReader::open(path)?;
+    let mut vectors = Vec::new();
+    while limit.map(|l| vectors.len() < l).unwrap_or(true) {
+        match reader.next_f32()? {
+            Some(v) => vectors.push(v),
+            None => break,
+        }
+    }
+    Ok(vectors)
+}
+
+pub fn read_ivecs<P: AsRef<Path>>(path: P, limit: Option<usize>) -> Result<Vec<Vec<i32>>> {
+    let mut reader = VecsReader::open(path)?;
+    let mut vectors = Vec::new();
+    while limit.map(|l| vectors.len() < l).unwrap_or(true) {
+        match reader.next_i32()? {
+            Some(v) => vectors.push(v),
+            None => break,
+        }
+    }
+    Ok(vectors)
+}
+
+//分批通过 insert_batch 导入 返回的 id 和文件里的顺序一致 ground truth 里的序号通过它转换成 id
+pub fn load<T: KVStore + Clone + Send + Sync, P: AsRef<Path>>(hnsw: &HNSW<T>, path: P, limit: Option<usize>, batch: usize) -> Result<Vec<u64>> {
+    let mut reader = VecsReader::open(path)?;
+    let mut ids = Vec::new();
+    let limit = limit.unwrap_or(usize::MAX);
+    loop {
+        let mut arrows = Vec::with_capacity(batch);
+        while arrows.len() < batch && ids.len() + arrows.len() < limit {
+            match reader.next_f32()? {
+                Some(v) => arrows.push(v),
+                None => break,
+            }
+        }
+        if arrows.is_empty() {
+            return Ok(ids);
+        }
+        ids.extend(hnsw.insert_batch(arrows)?);
+    }
+}
+
+//目录下所有文件的大小
+pub fn disk_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        size += if entry.file_type()?.is_dir() { disk_size(entry.path())? } else { entry.metadata()?.len() };
+    }
+    Ok(size)
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    pub ef: usize,
+    pub k: usize,
+    pub qps: f64,
+    pub recall: f64,
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ef {:>5} recall@{} {:.4} qps {:.1}", self.ef, self.k, self.recall, self.qps)
+    }
+}
+
+//ids 是 load 返回的 id  truth 是 ground truth 文件 每行是 base 文件里的序号 只比较前 k 个
+pub fn benchmark<T: KVStore + Clone + Send + Sync>(hnsw: &HNSW<T>, ids: &[u64], queries: &[Vec<f32>], truth: &[Vec<i32>], k: usize, ef: usize) -> Result<BenchReport> {
+    if queries.len() > truth.len() {
+        return Err(anyhow!("{} queries but only {} ground truth", queries.len(), truth.len()));
+    }
+    let params = SearchParams::new(k).ef(ef);
+    let (mut found, mut total) = (0, 0);
+    let start = Instant::now();
+    let results = queries.iter().map(|q| hnsw.search_with(q.clone(), &params)).collect::<Result<Vec<_>>>()?;
+    let elapsed = start.elapsed().as_secs_f64();
+    for (result, truth) in results.iter().zip(truth) {
+        let truth: Vec<u64> = truth.iter().take(k).filter_map(|&i| ids.get(i as usize)).copied().collect();
+        found += result.iter().filter(|r| truth.contains(&r.0)).count();
+        total += truth.len();
+    }
+    Ok(BenchReport { ef, k, qps: queries.len() as f64 / elapsed.max(f64::EPSILON), recall: if total == 0 { 1. } else { found as f64 / total as f64 } })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{hnsw::HNSW, Dist};
+    use crate::store::fjall::FjallStore;
+    use fjall::Config;
+    use std::io::Write;
+
+    fn write_vecs(path: &std::path::Path, vectors: &[Vec<u8>], size: usize) {
+        let mut file = std::fs::File::create(path).unwrap();
+        for v in vectors {
+            file.write_all(&((v.len() / size) as i32).to_le_bytes()).unwrap();
+            file.write_all(v).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join("arrowdb_test_dataset");
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let base: Vec<Vec<f32>> = (0..50).map(|i| vec![i as f32, (i * i) as f32, 1.]).collect();
+        write_vecs(&path.join("base.fvecs"), &base.iter().map(|v| v.iter().flat_map(|f| f.to_le_bytes()).collect()).collect::<Vec<_>>(), 4);
+        write_vecs(&path.join("query.bvecs"), &[vec![3, 9, 1], vec![10, 100, 1]], 1);
+        write_vecs(&path.join("truth.ivecs"), &[[3i32, 2].iter().flat_map(|i| i.to_le_bytes()).collect(), [10i32, 9].iter().flat_map(|i| i.to_le_bytes()).collect()], 4);
+
+        assert_eq!(super::read_vectors(path.join("base.fvecs"), Some(3)).unwrap(), base[..3].to_vec());
+        let space = Config::new(path.join("db")).open_transactional().unwrap();
+        let hnsw = HNSW::new(FjallStore::open(&space, "sift"), 16, 100, 16, Dist::L2);
+        let ids = super::load(&hnsw, path.join("base.fvecs"), None, 16).unwrap();
+        assert_eq!(ids.len(), 50);
+        assert_eq!(hnsw.get(ids[7]).unwrap(), Some(base[7].clone()));
+        let queries = super::read_vectors(path.join("query.bvecs"), None).unwrap();
+        assert_eq!(queries[1], vec![10., 100., 1.]);
+        let truth = super::read_ivecs(path.join("truth.ivecs"), None).unwrap();
+        assert_eq!(super::read_vectors(path.join("truth.ivecs"), None).unwrap(), vec![vec![3., 2.], vec![10., 9.]]);     //i32 按数值转换
+        let report = super::benchmark(&hnsw, &ids, &queries, &truth, 1, 50).unwrap();
+        assert_eq!(report.recall, 1.);
+        assert!(super::disk_size(path.join("db")).unwrap() > 0);
+        assert!(super::read_vectors(path.join("base.txt"), None).is_err());
+    }
+}
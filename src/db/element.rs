@@ -0,0 +1,314 @@
+//向量分量的类型 HNSW 按照元素类型保存向量 磁盘上是每个分量的小端字节
+//f16 和 bf16 只用来保存 计算距离时转换成 f32 i8 和 u8 用整数累加
+//Bits 是打包的二进制向量 每个字节 8 位 距离用 popcount 计算
+use super::Dist;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ElementType {
+    #[default]
+    F32,
+    F16,
+    BF16,
+    I8,
+    U8,
+    Binary,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct F16(u16);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct BF16(u16);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Bits(pub u8);
+
+impl Bits {
+    //第 i 位在第 i / 8 个字节的第 i % 8 位
+    pub fn pack(bits: &[bool]) -> Vec<Bits> {
+        bits.chunks(8).map(|c| Bits(c.iter().enumerate().fold(0, |b, (i, v)| b | (*v as u8) << i))).collect()
+    }
+
+    pub fn unpack(bits: &[Bits]) -> Vec<bool> {
+        bits.iter().flat_map(|b| (0..8).map(move |i| b.0 >> i & 1 == 1)).collect()
+    }
+
+    fn bytes(bits: &[Bits]) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(bits.as_ptr() as *const u8, bits.len()) }
+    }
+
+    //每次处理 8 个字节 返回 (|a & b|, |a | b|, |a ^ b|, |a|, |b|)
+    fn count(va: &[Bits], vb: &[Bits]) -> (u32, u32, u32, u32, u32) {
+        let (va, vb) = (Self::bytes(va), Self::bytes(vb));
+        let mut count = (0, 0, 0, 0, 0);
+        let mut add = |a: u64, b: u64| {
+            count.0 += (a & b).count_ones();
+            count.1 += (a | b).count_ones();
+            count.2 += (a ^ b).count_ones();
+            count.3 += a.count_ones();
+            count.4 += b.count_ones();
+        };
+        let (ca, cb) = (va.chunks_exact(8), vb.chunks_exact(8));
+        let (ra, rb) = (ca.remainder(), cb.remainder());
+        for (a, b) in ca.zip(cb) {
+            add(u64::from_le_bytes(a.try_into().unwrap()), u64::from_le_bytes(b.try_into().unwrap()));
+        }
+        for (a, b) in ra.iter().zip(rb) {
+            add(*a as u64, *b as u64);
+        }
+        count
+    }
+}
+
+impl F16 {
+    pub fn from_bits(bits: u16) -> Self { Self(bits) }
+
+    pub fn to_bits(self) -> u16 { self.0 }
+
+    //舍入到最近的偶数 超出范围的变成无穷大
+    pub fn from_f32(value: f32) -> Self {
+        let x = value.to_bits();
+        let sign = ((x >> 16) & 0x8000) as u16;
+        let exp = ((x >> 23) & 0xff) as i32;
+        let man = x & 0x7f_ffff;
+        if exp == 0xff {
+            return Self(sign | 0x7c00 | if man != 0 { 0x200 } else { 0 });
+        }
+        let e = exp - 127 + 15;
+        if e >= 0x1f {
+            return Self(sign | 0x7c00);
+        }
+        let (mut r, rem, half) = if e <= 0 {
+            if e < -10 {
+                return Self(sign);
+            }
+            let man = man | 0x80_0000;
+            let shift = (14 - e) as u32;
+            (man >> shift, man & ((1 << shift) - 1), 1 << (shift - 1))
+        } else {
+            (((e as u32) << 10) | (man >> 13), man & 0x1fff, 0x1000)
+        };
+        if rem > half || (rem == half && r & 1 == 1) {
+            r += 1;                                     //进位到指数也是正确的
+        }
+        Self(sign | r as u16)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        let sign = ((self.0 & 0x8000) as u32) << 16;
+        let exp = ((self.0 >> 10) & 0x1f) as u32;
+        let man = (self.0 & 0x3ff) as u32;
+        match exp {
+            0 => {
+                let v = man as f32 * (1. / (1 << 24) as f32);
+                if sign == 0 { v } else { -v }
+            }
+            0x1f => f32::from_bits(sign | 0x7f80_0000 | (man << 13)),
+            _ => f32::from_bits(sign | ((exp + 127 - 15) << 23) | (man << 13)),
+        }
+    }
+}
+
+impl BF16 {
+    pub fn from_bits(bits: u16) -> Self { Self(bits) }
+
+    pub fn to_bits(self) -> u16 { self.0 }
+
+    pub fn from_f32(value: f32) -> Self {
+        if value.is_nan() {
+            return Self(0x7fc0);
+        }
+        let bits = value.to_bits();
+        Self(((bits + 0x7fff + ((bits >> 16) & 1)) >> 16) as u16)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
+    }
+}
+
+impl std::fmt::Debug for F16 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "{}", self.to_f32()) }
+}
+
+impl std::fmt::Debug for BF16 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "{}", self.to_f32()) }
+}
+
+impl std::fmt::Debug for Bits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "{:08b}", self.0.reverse_bits()) }
+}
+
+pub trait Element: Copy + Default + PartialEq + std::fmt::Debug + Send + Sync + 'static {
+    const TYPE: ElementType;
+    const FLOAT: bool;                              //整数类型不能归一化
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+    fn write_le(self, buf: &mut Vec<u8>);            //磁盘上的编码 每个分量 size_of::<Self>() 个小端字节
+    fn read_le(bytes: &[u8]) -> Self;
+
+    fn distance(dist: &Dist, va: &[Self], vb: &[Self]) -> f32 {
+        match dist {
+            Dist::Custom(_) => dist.eval(&va.iter().map(|v| v.to_f32()).collect::<Vec<_>>(), &vb.iter().map(|v| v.to_f32()).collect::<Vec<_>>()),
+            dist => float_distance(dist, va.iter().map(|v| v.to_f32()), vb.iter().map(|v| v.to_f32())),
+        }
+    }
+}
+
+//和 anndists 的定义一致 L2 开平方 Cosine 有零向量时为 0
+pub(crate) fn float_distance<A: Iterator<Item = f32>, B: Iterator<Item = f32>>(dist: &Dist, va: A, vb: B) -> f32 {
+    let pairs = va.zip(vb);
+    match dist {
+        Dist::L1 => pairs.map(|(a, b)| (a - b).abs()).sum(),
+        Dist::L2 => pairs.map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt(),
+        Dist::Dot => -pairs.map(|(a, b)| a * b).sum::<f32>(),
+        Dist::CosineNormalized => (1. - pairs.map(|(a, b)| a * b).sum::<f32>()).max(0.),
+        Dist::Hamming => pairs.filter(|(a, b)| a != b).count() as f32,
+        Dist::Jaccard => {
+            let (min, max) = pairs.fold((0., 0.), |acc: (f32, f32), (a, b)| (acc.0 + a.min(b), acc.1 + a.max(b)));
+            if max > 0. { 1. - min / max } else { 0. }
+        }
+        _ => {
+            let (dot, na, nb) = pairs.fold((0., 0., 0.), |acc: (f32, f32, f32), (a, b)| (acc.0 + a * b, acc.1 + a * a, acc.2 + b * b));
+            if na > 0. && nb > 0. { (1. - dot / (na * nb).sqrt()).max(0.) } else { 0. }
+        }
+    }
+}
+
+impl Element for f32 {
+    const TYPE: ElementType = ElementType::F32;
+    const FLOAT: bool = true;
+    fn to_f32(self) -> f32 { self }
+    fn from_f32(value: f32) -> Self { value }
+    fn write_le(self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_le_bytes()) }
+    fn read_le(bytes: &[u8]) -> Self { f32::from_le_bytes(bytes.try_into().unwrap()) }
+    fn distance(dist: &Dist, va: &[f32], vb: &[f32]) -> f32 {
+        dist.eval(va, vb)
+    }
+}
+
+impl Element for F16 {
+    const TYPE: ElementType = ElementType::F16;
+    const FLOAT: bool = true;
+    fn to_f32(self) -> f32 { F16::to_f32(self) }
+    fn from_f32(value: f32) -> Self { F16::from_f32(value) }
+    fn write_le(self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.0.to_le_bytes()) }
+    fn read_le(bytes: &[u8]) -> Self { F16(u16::from_le_bytes(bytes.try_into().unwrap())) }
+}
+
+impl Element for BF16 {
+    const TYPE: ElementType = ElementType::BF16;
+    const FLOAT: bool = true;
+    fn to_f32(self) -> f32 { BF16::to_f32(self) }
+    fn from_f32(value: f32) -> Self { BF16::from_f32(value) }
+    fn write_le(self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.0.to_le_bytes()) }
+    fn read_le(bytes: &[u8]) -> Self { BF16(u16::from_le_bytes(bytes.try_into().unwrap())) }
+}
+
+//整数向量 分量的乘积和平方和用 i64 累加 不会溢出
+macro_rules! integer_element {
+    ($ty:ty, $name:ident) => {
+        impl Element for $ty {
+            const TYPE: ElementType = ElementType::$name;
+            const FLOAT: bool = false;
+            fn to_f32(self) -> f32 { self as f32 }
+            fn from_f32(value: f32) -> Self { value.round().clamp(<$ty>::MIN as f32, <$ty>::MAX as f32) as $ty }
+            fn write_le(self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_le_bytes()) }
+            fn read_le(bytes: &[u8]) -> Self { <$ty>::from_le_bytes(bytes.try_into().unwrap()) }
+            fn distance(dist: &Dist, va: &[$ty], vb: &[$ty]) -> f32 {
+                let pairs = va.iter().zip(vb).map(|(a, b)| (*a as i64, *b as i64));
+                match dist {
+                    Dist::L1 => pairs.map(|(a, b)| (a - b).abs()).sum::<i64>() as f32,
+                    Dist::L2 => (pairs.map(|(a, b)| (a - b) * (a - b)).sum::<i64>() as f32).sqrt(),
+                    Dist::Dot => -(pairs.map(|(a, b)| a * b).sum::<i64>() as f32),
+                    Dist::Cosine => {
+                        let (dot, na, nb) = pairs.fold((0, 0, 0), |acc, (a, b)| (acc.0 + a * b, acc.1 + a * a, acc.2 + b * b));
+                        if na > 0 && nb > 0 { (1. - dot as f64 / ((na as f64) * (nb as f64)).sqrt()).max(0.) as f32 } else { 0. }
+                    }
+                    dist => float_distance(dist, va.iter().map(|v| *v as f32), vb.iter().map(|v| *v as f32)),
+                }
+            }
+        }
+    };
+}
+
+integer_element!(i8, I8);
+integer_element!(u8, U8);
+
+//每一位看作 0 或 1 的分量 其它距离也按照这个定义计算
+impl Element for Bits {
+    const TYPE: ElementType = ElementType::Binary;
+    const FLOAT: bool = false;
+    fn to_f32(self) -> f32 { self.0 as f32 }
+    fn from_f32(value: f32) -> Self { Bits(u8::from_f32(value)) }
+    fn write_le(self, buf: &mut Vec<u8>) { buf.push(self.0) }
+    fn read_le(bytes: &[u8]) -> Self { Bits(bytes[0]) }
+    fn distance(dist: &Dist, va: &[Bits], vb: &[Bits]) -> f32 {
+        let (and, or, xor, na, nb) = Bits::count(va, vb);
+        match dist {
+            Dist::Hamming | Dist::L1 => xor as f32,
+            Dist::L2 => (xor as f32).sqrt(),
+            Dist::Jaccard => if or == 0 { 0. } else { 1. - and as f32 / or as f32 },
+            Dist::Dot => -(and as f32),
+            Dist::Custom(_) => dist.eval(&Bits::unpack(va).into_iter().map(|b| b as u8 as f32).collect::<Vec<_>>(), &Bits::unpack(vb).into_iter().map(|b| b as u8 as f32).collect::<Vec<_>>()),
+            _ => if na > 0 && nb > 0 { (1. - and as f32 / ((na as f32) * (nb as f32)).sqrt()).max(0.) } else { 0. },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bits, Element, BF16, F16};
+    use crate::db::{u8_to_vec, vec_to_u8, Dist};
+
+    #[test]
+    fn test_half() {
+        for v in [0., 1., -2.5, 0.1, 65504., -3.25, 7e-5] {
+            assert!((F16::from_f32(v).to_f32() - v).abs() <= v.abs() / 1024.);
+            assert!((BF16::from_f32(v).to_f32() - v).abs() <= v.abs() / 128.);
+        }
+        assert!((F16::from_f32(1e-6).to_f32() - 1e-6).abs() <= 1. / (1 << 25) as f32);       //非规格化数
+        assert_eq!(F16::from_f32(1.).to_bits(), 0x3c00);
+        assert_eq!(F16::from_f32(1e6).to_f32(), f32::INFINITY);
+        assert!(F16::from_f32(f32::NAN).to_f32().is_nan());
+        assert_eq!(BF16::from_f32(1.).to_bits(), 0x3f80);
+
+        let (a, b) = ([1i8, -2, 3], [4i8, 2, -1]);
+        let (fa, fb) = (a.map(|v| v as f32), b.map(|v| v as f32));
+        for dist in [Dist::L1, Dist::L2, Dist::Cosine, Dist::Dot] {
+            assert!((i8::distance(&dist, &a, &b) - dist.eval(&fa, &fb)).abs() < 1e-5);
+            assert!((F16::distance(&dist, &fa.map(F16::from_f32), &fb.map(F16::from_f32)) - dist.eval(&fa, &fb)).abs() < 1e-5);
+        }
+        assert_eq!(u8::from_f32(300.), 255);
+    }
+
+    #[test]
+    fn test_le_bytes() {
+        let half = [F16::from_f32(1.), F16::from_f32(-2.)];
+        assert_eq!(vec_to_u8(&half), vec![0x00, 0x3c, 0x00, 0xc0]);
+        assert_eq!(u8_to_vec::<F16>(&vec_to_u8(&half)), half);
+        assert_eq!(vec_to_u8(&[1f32]), 1f32.to_le_bytes().to_vec());
+        let bytes = [0u8, 0x80, 0x3f, 0xff, 0x01];              //不对齐的切片也可以解码
+        assert_eq!(u8_to_vec::<BF16>(&bytes[1..]), vec![BF16::from_f32(1.), BF16(0x01ff)]);
+        assert_eq!(u8_to_vec::<i8>(&[0xff, 1]), vec![-1, 1]);
+        assert_eq!(u8_to_vec::<f32>(&vec_to_u8(&[0.5f32, -3.])), vec![0.5, -3.]);
+    }
+
+    #[test]
+    fn test_bits() {
+        let a: Vec<bool> = (0..80).map(|i| i % 3 == 0).collect();
+        let b: Vec<bool> = (0..80).map(|i| i % 2 == 0).collect();
+        let (pa, pb) = (Bits::pack(&a), Bits::pack(&b));
+        assert_eq!(pa.len(), 10);
+        assert_eq!(Bits::unpack(&pa), a);
+        let (fa, fb): (Vec<f32>, Vec<f32>) = (a.iter().map(|v| *v as u8 as f32).collect(), b.iter().map(|v| *v as u8 as f32).collect());
+        for dist in [Dist::Hamming, Dist::Jaccard, Dist::L1, Dist::L2, Dist::Dot, Dist::Cosine] {
+            assert!((Bits::distance(&dist, &pa, &pb) - dist.eval(&fa, &fb)).abs() < 1e-5, "{:?}", dist);
+        }
+        assert_eq!(Bits::distance(&Dist::Hamming, &pa, &pb), 39.);             //27 + 40 - 2 * 14
+    }
+}
@@ -0,0 +1,103 @@
+//召回率评估 用暴力搜索的结果作为标准答案 比较不同 ef 下 HNSW::search 的召回率 延迟 和距离计算次数
+use super::hnsw::HNSW;
+use super::search::SearchParams;
+use crate::store::KVStore;
+use anyhow::{anyhow, Result};
+use rand::prelude::*;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct EvalReport {
+    pub ef: usize,
+    pub k: usize,
+    pub queries: usize,
+    pub recall: f64,                            //recall@k
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub distance_computations: f64,             //平均每个查询的距离计算次数
+}
+
+impl std::fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ef {:>5} recall@{} {:.4} p50 {:?} p95 {:?} p99 {:?} dist/query {:.1} ({} queries)", self.ef, self.k, self.recall, self.p50, self.p95, self.p99, self.distance_computations, self.queries)
+    }
+}
+
+//从集合里随机取 number 个向量作为查询
+pub fn sample_queries<T: KVStore + Clone + Send + Sync>(hnsw: &HNSW<T>, number: usize) -> Result<Vec<Vec<f32>>> {
+    let ids = hnsw.ids()?;
+    let ids: Vec<u64> = ids.choose_multiple(&mut thread_rng(), number).copied().collect();
+    Ok(hnsw.get_many(&ids)?.into_iter().flatten().collect())
+}
+
+//文本文件 每行一个向量 数值之间用空格或者逗号分隔
+pub fn load_queries(path: &str) -> Result<Vec<Vec<f32>>> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.split(|c: char| c == ',' || c.is_whitespace()).filter(|v| !v.is_empty()).map(|v| v.parse::<f32>().map_err(|e| anyhow!("{}: {}", v, e))).collect())
+        .collect()
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+pub fn evaluate<T: KVStore + Clone + Send + Sync>(hnsw: &HNSW<T>, queries: &[Vec<f32>], k: usize, efs: &[usize]) -> Result<Vec<EvalReport>> {
+    let truth: Vec<Vec<u64>> = queries.iter().map(|q| Ok(hnsw.search_exact(q.clone(), k)?.into_iter().map(|r| r.0).collect())).collect::<Result<_>>()?;
+    let mut reports = Vec::new();
+    for &ef in efs {
+        let params = SearchParams::new(k).ef(ef);
+        let mut latency = Vec::with_capacity(queries.len());
+        let (mut found, mut total) = (0, 0);
+        let count = hnsw.distance_count();
+        for (query, truth) in queries.iter().zip(&truth) {
+            let start = Instant::now();
+            let result = hnsw.search_with(query.clone(), &params)?;
+            latency.push(start.elapsed());
+            found += result.iter().filter(|r| truth.contains(&r.0)).count();
+            total += truth.len();
+        }
+        let count = hnsw.distance_count() - count;
+        latency.sort();
+        reports.push(EvalReport {
+            ef,
+            k,
+            queries: queries.len(),
+            recall: if total == 0 { 1. } else { found as f64 / total as f64 },
+            p50: percentile(&latency, 0.5),
+            p95: percentile(&latency, 0.95),
+            p99: percentile(&latency, 0.99),
+            distance_computations: count as f64 / queries.len().max(1) as f64,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{hnsw::HNSW, Dist};
+    use crate::store::fjall::FjallStore;
+    use fjall::Config;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_evaluate() {
+        let path = std::env::temp_dir().join("arrowdb_test_eval");
+        let _ = std::fs::remove_dir_all(&path);
+        let space = Config::new(path).open_transactional().unwrap();
+        let hnsw = HNSW::new(FjallStore::open(&space, "eval"), 16, 100, 16, Dist::L2);
+        let mut rng = StdRng::seed_from_u64(7);
+        hnsw.insert_batch((0..500).map(|_| (0..8).map(|_| rng.gen::<f32>()).collect()).collect()).unwrap();
+        let queries = super::sample_queries(&hnsw, 20).unwrap();
+        assert_eq!(queries.len(), 20);
+        let reports = super::evaluate(&hnsw, &queries, 10, &[10, 100]).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports[1].recall >= 0.9);
+        assert!(reports[1].distance_computations > reports[0].distance_computations);
+    }
+}
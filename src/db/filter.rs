@@ -0,0 +1,102 @@
+//payload 上的过滤条件 在 search_layer 里面判断 不满足条件的点仍然参与导航 但是不会被返回
+use super::payload::{Payload, Value};
+use serde::{Deserialize, Serialize};
+use std::ops::{Bound, RangeBounds};
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Filter {
+    Eq(String, Value),
+    In(String, Vec<Value>),
+    Range(String, Bound<f64>, Bound<f64>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(v) => Some(*v as f64),
+        Value::Float(v) => Some(*v),
+        _ => None,
+    }
+}
+
+fn value_eq(a: &Value, b: &Value) -> bool {
+    match (as_f64(a), as_f64(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+//数组类型的字段 只要有一个元素满足就可以
+fn any_value<F: Fn(&Value) -> bool>(value: &Value, f: F) -> bool {
+    match value {
+        Value::Array(values) => values.iter().any(f),
+        value => f(value),
+    }
+}
+
+impl Filter {
+    pub fn eq<V: Into<Value>>(field: &str, value: V) -> Self { Self::Eq(field.into(), value.into()) }
+
+    pub fn is_in<V: Into<Value>>(field: &str, values: Vec<V>) -> Self { Self::In(field.into(), values.into_iter().map(|v| v.into()).collect()) }
+
+    pub fn range<R: RangeBounds<f64>>(field: &str, range: R) -> Self { Self::Range(field.into(), range.start_bound().cloned(), range.end_bound().cloned()) }
+
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Self::And(mut filters) => {
+                filters.push(other);
+                Self::And(filters)
+            }
+            filter => Self::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Self::Or(mut filters) => {
+                filters.push(other);
+                Self::Or(filters)
+            }
+            filter => Self::Or(vec![filter, other]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self { Self::Not(Box::new(self)) }
+
+    pub fn matches(&self, payload: &Payload) -> bool {
+        match self {
+            Self::Eq(field, value) => payload.get(field).map(|v| any_value(v, |v| value_eq(v, value))).unwrap_or(false),
+            Self::In(field, values) => payload.get(field).map(|v| any_value(v, |v| values.iter().any(|value| value_eq(v, value)))).unwrap_or(false),
+            Self::Range(field, start, end) => payload.get(field).map(|v| any_value(v, |v| as_f64(v).map(|v| (*start, *end).contains(&v)).unwrap_or(false))).unwrap_or(false),
+            Self::And(filters) => filters.iter().all(|f| f.matches(payload)),
+            Self::Or(filters) => filters.iter().any(|f| f.matches(payload)),
+            Self::Not(filter) => !filter.matches(payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::db::payload::payload;
+
+    #[test]
+    fn test_matches() {
+        let doc = payload([("tenant", "a".into()), ("year", 2020.into()), ("score", 0.5.into()), ("tags", vec!["x", "y"].into())]);
+        assert!(Filter::eq("tenant", "a").matches(&doc));
+        assert!(!Filter::eq("tenant", "b").matches(&doc));
+        assert!(Filter::eq("year", 2020.0).matches(&doc));
+        assert!(Filter::eq("tags", "y").matches(&doc));
+        assert!(Filter::is_in("tenant", vec!["b", "a"]).matches(&doc));
+        assert!(Filter::range("year", 2000.0..2021.0).matches(&doc));
+        assert!(!Filter::range("score", ..0.5).matches(&doc));
+        assert!(Filter::range("score", ..=0.5).matches(&doc));
+        assert!(!Filter::range("missing", ..).matches(&doc));
+        assert!(Filter::eq("tenant", "a").and(Filter::eq("tenant", "b").not()).matches(&doc));
+        assert!(!Filter::eq("tenant", "a").and(Filter::eq("year", 2021)).matches(&doc));
+        assert!(Filter::eq("tenant", "b").or(Filter::eq("year", 2020)).matches(&doc));
+    }
+}
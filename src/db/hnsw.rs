@@ -1,32 +1,82 @@
 #![allow(dead_code)]
 use super::layer::LayerGenerator;
 use super::order_id::{LevelVec, OrderId, Point};
+use super::filter::Filter;
+use super::index::{IndexKind, PayloadIndex};
+use super::key::Key;
+use super::payload::Payload;
+use super::quantize::Quantizer;
+use super::search::{Hit, SearchOptions, SearchParams, Strategy};
 use super::unique_id::QueryID;
+use super::element::{Element, ElementType};
 use super::Dist;
 use super::PersistID;
 use crate::store::KVStore;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
 use scc::HashMap;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use rayon::prelude::*;
 
 #[derive(Clone)]
-pub struct HNSW<T: KVStore + Clone + Send + Sync> {
+pub struct HNSW<T: KVStore + Clone + Send + Sync, E: Element = f32> {
     max_nb: usize,
     ef: usize,
     layer_g: Arc<Mutex<LayerGenerator>>,
     query_id: QueryID,
     pub(crate) dist_f: Dist,
-    arrows: Arc<HashMap<u64, Arc<Vec<f32>>>>,                 //每个 id 的向量数据
-    neighbors: Arc<HashMap<u64, Arc<RwLock<LevelVec<f32>>>>>, //每个 id 的邻居数据
+    arrows: Arc<HashMap<u64, Arc<Vec<E>>>>,                 //每个 id 的向量数据
+    neighbors: Arc<HashMap<u64, Arc<RwLock<LevelVec<E>>>>>, //每个 id 的邻居数据
+    dropped: Arc<AtomicBool>,                                 //集合已经被删除 所有的 clone 都不能再使用
+    indexes: Arc<RwLock<FxHashMap<String, PayloadIndex<T>>>>,  //payload 字段的二级索引
+    upserting: Arc<HashMap<Bytes, Arc<Mutex<()>>>>,           //正在 upsert 的 key 同一个 key 的 upsert 在锁上排队
+    deleted: Arc<scc::HashSet<u64>>,                          //已经删除的 id 仍然参与导航 但是不会被返回
+    vacuum_lock: Arc<Mutex<()>>,
+    flat_threshold: usize,                                    //向量数量不超过这个值时 使用暴力搜索 0 表示不使用
+    dist_count: Arc<AtomicU64>,                               //距离计算的次数 用于评估
+    quantize: bool,                                           //打开量化以后 训练好之前仍然使用原来的向量
+    quantizer: Arc<RwLock<Option<Arc<Quantized>>>>,
     store: T,
 }
 
+//量化以后 内存里只保存每个 id 的 int8 编码 编码和量化器一起替换 旧范围的编码不会用新的范围解码
+struct Quantized {
+    quantizer: Quantizer,
+    codes: HashMap<u64, Arc<Vec<u8>>>,
+}
+
+impl Quantized {
+    fn new(quantizer: Quantizer) -> Arc<Self> {
+        Arc::new(Self { quantizer, codes: HashMap::new() })
+    }
+}
+
+//upsert 结束 (包括 panic) 时 没有其它线程在等待的 key 从表里删除
+struct KeyRelease<'a> {
+    locks: &'a HashMap<Bytes, Arc<Mutex<()>>>,
+    key: Bytes,
+}
+
+impl Drop for KeyRelease<'_> {
+    fn drop(&mut self) {
+        self.locks.remove_if(&self.key, |lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+const QUANTIZER_KEY: &[u8] = b"__quantizer__";
+const QUANTIZE_TRAIN_SIZE: usize = 1000;                      //打开量化的集合 向量数量达到这个值时自动训练
+const RESCORE_FACTOR: usize = 4;                              //量化搜索时 用原始向量重新计算距离的候选数量是 k 的倍数
+
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::collections::BinaryHeap;
-impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
+impl<T: KVStore + Clone + Send + Sync, E: Element> HNSW<T, E> {
     pub fn new(store: T, max_nb: usize, ef: usize, max_level: usize, dist_f: Dist) -> Self {
+        let deleted = scc::HashSet::new();
+        for (key, _) in store.scan(Bytes::from_static(b"D")).flatten() {
+            let _ = deleted.insert(u64::from_le_bytes(key[1..].try_into().unwrap_or_default()));
+        }
         Self {
             max_nb,
             ef,
@@ -35,13 +85,148 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
             dist_f,
             arrows: Arc::new(HashMap::new()),
             neighbors: Arc::new(HashMap::new()),
+            dropped: Arc::new(AtomicBool::new(false)),
+            indexes: Arc::new(RwLock::new(FxHashMap::default())),
+            upserting: Arc::new(HashMap::new()),
+            deleted: Arc::new(deleted),
+            vacuum_lock: Arc::new(Mutex::new(())),
+            flat_threshold: 0,
+            dist_count: Arc::new(AtomicU64::new(0)),
+            quantize: false,
+            quantizer: Arc::new(RwLock::new(None)),
             store,
         }
     }
 
-    pub fn set_arrow(&self, id: u64, arrow: Vec<f32>) -> Result<()> {
-        self.arrows.update(&id, |_, v| *v = Arc::new(arrow));
-        self.save_arrow(id)
+    //标量量化 之前训练好的 quantizer 保存在 store 里
+    pub fn with_quantization(mut self) -> Self {
+        self.quantize = true;
+        let quantizer = self.store.try_get(Bytes::from_static(QUANTIZER_KEY)).ok().flatten().and_then(|q| rmp_serde::from_slice::<Quantizer>(&q).ok());
+        *self.quantizer.write().unwrap() = quantizer.map(Quantized::new);
+        self
+    }
+
+    fn quantizer(&self) -> Option<Arc<Quantized>> {
+        if self.quantize { self.quantizer.read().unwrap().clone() } else { None }
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        self.quantizer().is_some()
+    }
+
+    //用所有存储的向量学习每一维的范围 然后内存里只保留 int8 编码 重复调用会重新训练
+    pub fn train_quantizer(&self) -> Result<()> {
+        self.check()?;
+        if !self.quantize {
+            return Err(anyhow!("quantization is not enabled on this collection"));
+        }
+        let arrows = self.store.scan(Bytes::from_static(b"A")).flatten().filter(|(key, _)| !self.deleted.contains(&u64::from_le_bytes(key[1..].try_into().unwrap_or_default())));
+        let quantizer = match Quantizer::train(arrows.map(|(_, value)| super::u8_to_vec::<E>(&value))) {
+            Some(quantizer) => quantizer,
+            None => return Ok(()),
+        };
+        self.store.set(Bytes::from_static(QUANTIZER_KEY), Bytes::from_owner(rmp_serde::to_vec(&quantizer)?))?;
+        *self.quantizer.write().unwrap() = Some(Quantized::new(quantizer));
+        self.arrows.clear();
+        self.neighbors.scan(|_, neighbor| neighbor.write().unwrap().value.iter_mut().for_each(|n| n.point.arrow = None));
+        Ok(())
+    }
+
+    fn auto_train(&self) -> Result<()> {
+        if self.quantize && self.quantizer.read().unwrap().is_none() && self.len() >= QUANTIZE_TRAIN_SIZE {
+            self.train_quantizer()?;
+        }
+        Ok(())
+    }
+
+    pub fn with_flat_threshold(mut self, flat_threshold: usize) -> Self {
+        self.flat_threshold = flat_threshold;
+        self
+    }
+
+    pub(crate) fn close(&self) {
+        self.dropped.store(true, Ordering::Release);
+        self.arrows.clear();
+        if let Some(q) = self.quantizer() {
+            q.codes.clear();
+        }
+        self.neighbors.clear();
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.dropped.load(Ordering::Acquire) { Err(anyhow!("collection has been dropped")) }
+        else { Ok(()) }
+    }
+
+    pub fn set_arrow(&self, id: u64, arrow: Vec<E>) -> Result<()> {
+        self.update(id, arrow)
+    }
+
+    //更换向量 先断开原来的边 再按照 insert_id 的方式重新连接到图上
+    pub fn update(&self, id: u64, arrow: Vec<E>) -> Result<()> {
+        let arrow = self.dist_f.prepare(arrow)?;
+        self.check()?;
+        if self.deleted.contains(&id) || self.load_arrow(id)?.is_none() {
+            return Err(anyhow!("id {} not found", id));
+        }
+        let neighbor = self.get_neighbor(&mut Point::new(id, 0))?;
+        let old = std::mem::take(&mut neighbor.write().unwrap().value);
+        let level = self.node_level(id)?;
+        let mut updated = FxHashSet::<u64>::default();
+        updated.insert(id);
+        for n in &old {
+            if let Ok(n_neighbor) = self.get_neighbor(&mut n.point.clone()) {
+                let mut n_neighbor = n_neighbor.write().unwrap();
+                while n_neighbor.remove_id(id) {
+                    updated.insert(n.point.id());
+                }
+            }
+        }
+        self.save_arrow(id, arrow)?;
+        for r in self.referrers(id)? {                            //剪枝以后边是单向的 其它指向 id 的边距离已经不对了
+            if updated.contains(&r) || self.deleted.contains(&r) {
+                continue;
+            }
+            let mut point = Point::new(r, 0);
+            if let (Ok(dist), Ok(neighbor)) = (self.distance(&mut point, &mut Point::new(id, 0)), self.get_neighbor(&mut point)) {
+                if neighbor.write().unwrap().set_dist(id, dist) {
+                    updated.insert(r);
+                }
+            }
+        }
+        let (max_level, entry) = self.store.entry();
+        let start = if entry == id {                                //自己是入口点 从原来最高层的邻居开始
+            old.iter().filter(|n| n.point.id() != id).max_by_key(|n| n.point.level()).map(|n| (n.point.level(), n.point.id()))
+        } else {
+            Some((max_level, entry))
+        };
+        if let Some((max_level, entry)) = start {
+            updated.extend(self.link(id, level, max_level, entry)?);
+        }
+        for id in updated {
+            self.save_neighbor(id)?;
+        }
+        Ok(())
+    }
+
+    //邻居列表里有 id 的点 需要扫描所有的 N
+    fn referrers(&self, id: u64) -> Result<Vec<u64>> {
+        let mut referrers = Vec::new();
+        for kv in self.store.scan(Bytes::from_static(b"N")) {
+            let (key, value) = kv?;
+            if LevelVec::<E>::bytes_contain(&value, id) {
+                referrers.push(u64::from_le_bytes(key[1..].try_into()?));
+            }
+        }
+        Ok(referrers)
+    }
+
+    //节点插入时生成的层数 邻居列表里还有更高层的入口点 不能用来推断层数
+    fn node_level(&self, id: u64) -> Result<usize> {
+        match self.store.try_get(HNSW::<T>::get_id(b"L", id))? {
+            Some(level) => Ok(u64::from_le_bytes(level.as_ref().try_into()?) as usize),
+            None => Ok(0),
+        }
     }
 
     fn get_id(prefix: &[u8], id: u64) -> Bytes {
@@ -51,36 +236,257 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
         b.freeze()
     }
 
-    pub fn remove(&self, id: u64) {
-        let (_, entry_id) = self.store.entry();
-        if id != entry_id {
-            let _ = self.store.remove(HNSW::<T>::get_id(b"A", id));
-            self.arrows.remove(&id);
+    //只是标记删除 向量和邻居还保留用于导航 由 vacuum 真正清理
+    pub fn remove(&self, id: u64) -> Result<()> {
+        self.check()?;
+        if self.deleted.contains(&id) || self.load_arrow(id)?.is_none() {
+            return Err(anyhow!("id {} not found", id));
+        }
+        self.store.set(HNSW::<T>::get_id(b"D", id), Bytes::new())?;
+        let _ = self.deleted.insert(id);
+        self.write_payload(id, None)?;
+        if let Some(key) = self.key_of(id)? {
+            self.store.remove(HNSW::<T>::key_id(&key))?;
+            self.store.remove(HNSW::<T>::get_id(b"R", id))?;
+        }
+        Ok(())
+    }
+
+    //创建以来图上距离计算的总次数
+    pub fn distance_count(&self) -> u64 {
+        self.dist_count.load(Ordering::Relaxed)
+    }
+
+    //所有没有被删除的 id
+    pub fn ids(&self) -> Result<Vec<u64>> {
+        self.check()?;
+        let mut ids = Vec::new();
+        for kv in self.store.scan(Bytes::from_static(b"A")) {
+            let id = u64::from_le_bytes(kv?.0[1..].try_into()?);
+            if !self.deleted.contains(&id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn is_deleted(&self, id: u64) -> bool {
+        self.deleted.contains(&id)
+    }
+
+    //没有被删除的向量数量
+    pub fn len(&self) -> usize {
+        (self.store.size() as usize).saturating_sub(self.deleted.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn key_id(key: &Key) -> Bytes {
+        let key = key.to_bytes();
+        let mut b = BytesMut::with_capacity(key.len() + 1);
+        b.extend_from_slice(b"K");
+        b.extend_from_slice(&key);
+        b.freeze()
+    }
+
+    //外部 key 对应的内部 id
+    pub fn id_of(&self, key: &Key) -> Result<Option<u64>> {
+        Ok(self.store.try_get(HNSW::<T>::key_id(key))?.map(|buf| u64::from_le_bytes(buf.as_ref().try_into().unwrap_or_default())))
+    }
+
+    //内部 id 对应的外部 key 通过 insert 插入的没有 key
+    pub fn key_of(&self, id: u64) -> Result<Option<Key>> {
+        self.store.try_get(HNSW::<T>::get_id(b"R", id))?.map(|buf| Key::from_bytes(&buf)).transpose()
+    }
+
+    //key 不存在时插入新的向量 存在时替换原来的向量 返回内部 id
+    pub fn upsert(&self, key: Key, arrow: Vec<E>) -> Result<u64> {
+        self.check()?;
+        let kid = HNSW::<T>::key_id(&key);
+        let _release = KeyRelease { locks: &self.upserting, key: kid.clone() };
+        let lock = self.upserting.entry(kid.clone()).or_default().get().clone();
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());     //前一个 upsert panic 时锁会中毒 但是 key 的数据没有问题
+        match self.id_of(&key) {
+            Ok(Some(id)) => self.update(id, arrow).map(|_| id),
+            Ok(None) => self.insert(arrow).and_then(|id| {
+                self.store.set(HNSW::<T>::get_id(b"R", id), key.to_bytes())?;
+                self.store.set(kid.clone(), Bytes::copy_from_slice(&id.to_le_bytes()))?;
+                Ok(id)
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    //rebuild 为 true 时 用已经存在的 payload 建立索引
+    pub(crate) fn add_index(&self, field: &str, kind: IndexKind, store: T, rebuild: bool) -> Result<()> {
+        let index = PayloadIndex::new(kind, store);
+        if rebuild {
+            for kv in self.store.scan(Bytes::from_static(b"P")) {
+                let (key, value) = kv?;
+                let id = u64::from_le_bytes(key[1..].try_into()?);
+                index.insert(id, field, &rmp_serde::from_slice(&value)?)?;
+            }
+        }
+        self.indexes.write().unwrap().insert(field.into(), index);
+        Ok(())
+    }
+
+    //写入或者删除 payload 同时更新索引
+    fn write_payload(&self, id: u64, payload: Option<&Payload>) -> Result<()> {
+        let indexes = self.indexes.read().unwrap();
+        if !indexes.is_empty() {
+            if let Some(old) = self.load_payload(id)? {
+                for (field, index) in indexes.iter() {
+                    index.remove(id, field, &old)?;
+                }
+            }
+        }
+        let pid = HNSW::<T>::get_id(b"P", id);
+        if let Some(payload) = payload {
+            for (field, index) in indexes.iter() {
+                index.insert(id, field, payload)?;
+            }
+            self.store.set(pid, Bytes::from_owner(rmp_serde::to_vec(payload)?))
+        } else {
+            self.store.remove(pid)
+        }
+    }
+
+    pub fn set_payload(&self, id: u64, payload: &Payload) -> Result<()> {
+        self.check()?;
+        if self.deleted.contains(&id) || self.load_arrow(id)?.is_none() {
+            return Err(anyhow!("id {} not found", id));
+        }
+        self.write_payload(id, Some(payload))
+    }
+
+    fn load_payload(&self, id: u64) -> Result<Option<Payload>> {
+        match self.store.try_get(HNSW::<T>::get_id(b"P", id))? {
+            Some(slice) => Ok(Some(rmp_serde::from_slice(&slice)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_payload(&self, id: u64) -> Result<Option<Payload>> {
+        self.check()?;
+        self.load_payload(id)
+    }
+
+    pub fn delete_payload(&self, id: u64) -> Result<()> {
+        self.check()?;
+        self.write_payload(id, None)
+    }
+
+    //通过索引得到满足条件的 id 集合 bool 表示集合是否精确 不精确时 还需要再检查 payload
+    fn index_ids(&self, filter: &Filter) -> Result<Option<(FxHashSet<u64>, bool)>> {
+        match filter {
+            Filter::Eq(field, _) | Filter::In(field, _) | Filter::Range(field, _, _) => {
+                if let Some(index) = self.indexes.read().unwrap().get(field) {
+                    return Ok(index.ids(filter)?.map(|ids| (ids, true)));
+                }
+                Ok(None)
+            }
+            Filter::And(filters) => {
+                let mut result: Option<(FxHashSet<u64>, bool)> = None;
+                let mut exact = true;
+                for f in filters {
+                    match self.index_ids(f)? {
+                        Some((ids, e)) => {
+                            exact &= e;
+                            result = Some(match result {
+                                Some((r, _)) => (r.intersection(&ids).copied().collect(), true),
+                                None => (ids, true),
+                            });
+                        }
+                        None => exact = false,
+                    }
+                }
+                Ok(result.map(|(ids, _)| (ids, exact)))
+            }
+            Filter::Or(filters) => {
+                let mut result = FxHashSet::default();
+                let mut exact = true;
+                for f in filters {
+                    match self.index_ids(f)? {
+                        Some((ids, e)) => {
+                            exact &= e;
+                            result.extend(ids);
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some((result, exact)))
+            }
+            Filter::Not(_) => Ok(None),
+        }
+    }
+
+    //满足条件的 payload 数量
+    pub fn count(&self, filter: &Filter) -> Result<usize> {
+        self.check()?;
+        match self.index_ids(filter)? {
+            Some((ids, true)) => Ok(ids.len()),
+            Some((ids, false)) => Ok(ids.into_iter().filter(|id| self.load_payload(*id).ok().flatten().map(|p| filter.matches(&p)).unwrap_or(false)).count()),
+            None => {
+                let mut count = 0;
+                for kv in self.store.scan(Bytes::from_static(b"P")) {
+                    let payload: Payload = rmp_serde::from_slice(&kv?.1)?;
+                    if filter.matches(&payload) {
+                        count += 1;
+                    }
+                }
+                Ok(count)
+            }
         }
     }
 
-    fn get_arrow(&self, id: u64) -> Result<Arc<Vec<f32>>> {
-        if !self.arrows.contains(&id) {
-            let slice = self.store.get(HNSW::<T>::get_id(b"A", id))?;
-            let arrow: Vec<f32> = super::u8_to_vec(slice.to_vec());
-            let _ = self.arrows.insert(id, Arc::new(arrow));
+    fn load_arrow(&self, id: u64) -> Result<Option<Arc<Vec<E>>>> {
+        if let Some(arrow) = self.arrows.read(&id, |_, v| v.clone()) {
+            return Ok(Some(arrow));
         }
-        Ok(self.arrows.get(&id).unwrap().get().clone())
+        if let Some(slice) = self.store.try_get(HNSW::<T>::get_id(b"A", id))? {
+            let arrow = Arc::new(super::u8_to_vec::<E>(&slice));
+            if !self.is_quantized() {                      //量化以后 原始向量不留在内存里
+                let _ = self.arrows.insert(id, arrow.clone());
+            }
+            Ok(Some(arrow))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_arrow(&self, id: u64) -> Result<Arc<Vec<E>>> {
+        self.load_arrow(id)?.ok_or(anyhow!("id {} not found", id))
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<Vec<E>>> {
+        self.check()?;
+        if self.deleted.contains(&id) {
+            return Ok(None);
+        }
+        Ok(self.load_arrow(id)?.map(|arrow| arrow.as_ref().clone()))
+    }
+
+    pub fn get_many(&self, ids: &[u64]) -> Result<Vec<Option<Vec<E>>>> {
+        self.check()?;
+        ids.iter().map(|id| if self.deleted.contains(id) { Ok(None) } else { Ok(self.load_arrow(*id)?.map(|arrow| arrow.as_ref().clone())) }).collect()
     }
 
-    fn get_neighbor(&self, point: &mut Point<f32>) -> Result<Arc<RwLock<LevelVec<f32>>>> {
+    fn get_neighbor(&self, point: &mut Point<E>) -> Result<Arc<RwLock<LevelVec<E>>>> {
         if point.neighbor.is_none() {
             let id = point.id();
-            if !self.neighbors.contains(&id) {
-                let slice = self.store.get(HNSW::<T>::get_id(b"N", id))?;
-                let ids: Vec<(u64, f32)> = super::u8_to_vec(slice.to_vec());
-                let mut neighbor = LevelVec::<f32>::default();
-                ids.into_iter().for_each(|(id, dist)| {
-                    neighbor.push(OrderId::new(id, dist), None);
-                });
-                let _ = self.neighbors.insert(id, Arc::new(RwLock::new(neighbor)));
-            }
-            let neighbor = self.neighbors.get(&id).unwrap().get().clone();
+            //vacuum 和 close 可能同时删除缓存 不能先 contains 再 get
+            let neighbor = match self.neighbors.read(&id, |_, v| v.clone()) {
+                Some(neighbor) => neighbor,
+                None => {
+                    self.check()?;
+                    let slice = self.store.get(HNSW::<T>::get_id(b"N", id))?;
+                    let neighbor = LevelVec::<E>::from_bytes(&slice);
+                    self.neighbors.entry(id).or_insert_with(|| Arc::new(RwLock::new(neighbor))).get().clone()
+                }
+            };
             point.neighbor.replace(neighbor.clone());
             Ok(neighbor)
         } else {
@@ -88,7 +494,32 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
         }
     }
 
-    fn distance(&self, point: &mut Point<f32>, other: &mut Point<f32>) -> Result<f32> {
+    fn load_codes(&self, q: &Quantized, id: u64) -> Result<Arc<Vec<u8>>> {
+        if let Some(codes) = q.codes.read(&id, |_, v| v.clone()) {
+            return Ok(codes);
+        }
+        let codes = Arc::new(q.quantizer.encode(&self.get_arrow(id)?));
+        let _ = q.codes.insert(id, codes.clone());
+        Ok(codes)
+    }
+
+    //量化以后 已经存储的点使用编码 只有查询向量 (以及训练之前加载的点) 带着原始向量
+    //解码和转换都在迭代器里完成 每一步不需要分配新的 Vec
+    fn quantized_distance(&self, q: &Quantized, point: &Point<E>, other: &Point<E>) -> Result<f32> {
+        let (dist, quantizer) = (&self.dist_f, &q.quantizer);
+        Ok(match (&point.arrow, &other.arrow) {
+            (Some(a), Some(b)) => E::distance(dist, a, b),
+            (Some(a), None) => Quantizer::distance(dist, a.iter().map(|v| v.to_f32()), quantizer.decode(&self.load_codes(q, other.id())?)),
+            (None, Some(b)) => Quantizer::distance(dist, quantizer.decode(&self.load_codes(q, point.id())?), b.iter().map(|v| v.to_f32())),
+            (None, None) => Quantizer::distance(dist, quantizer.decode(&self.load_codes(q, point.id())?), quantizer.decode(&self.load_codes(q, other.id())?)),
+        })
+    }
+
+    fn distance(&self, point: &mut Point<E>, other: &mut Point<E>) -> Result<f32> {
+        if let Some(q) = self.quantizer() {
+            self.dist_count.fetch_add(1, Ordering::Relaxed);
+            return self.quantized_distance(&q, point, other);
+        }
         if point.arrow.is_none() {
             let arrow = self.get_arrow(point.id())?;
             point.arrow.replace(arrow);
@@ -97,40 +528,46 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
             let arrow = self.get_arrow(other.id())?;
             other.arrow.replace(arrow);
         }
-        Ok(self.dist_f.eval(point.arrow.as_ref().map(|a| a.as_slice()).unwrap(), other.arrow.as_ref().map(|a| a.as_slice()).unwrap()))
+        self.dist_count.fetch_add(1, Ordering::Relaxed);
+        Ok(E::distance(&self.dist_f, point.arrow.as_ref().map(|a| a.as_slice()).unwrap(), other.arrow.as_ref().map(|a| a.as_slice()).unwrap()))
     }
 
-    fn search_layer(&self, id: &mut Point<f32>, entry: &mut Point<f32>, ef: usize, level: usize) -> Result<BinaryHeap<OrderId<f32>>> {
+    //filter 不为空时 不满足条件的点仍然作为候选参与导航 但是不会进入返回结果
+    fn search_layer(&self, id: &mut Point<E>, entry: &mut Point<E>, ef: usize, level: usize, filter: Option<&dyn Fn(u64) -> bool>) -> Result<BinaryHeap<OrderId<E>>> {
         let skiplist_size = ef.max(2);
-        let mut return_points = BinaryHeap::<OrderId<f32>>::with_capacity(skiplist_size);
+        let allowed = |id: u64| filter.map(|f| f(id)).unwrap_or(true);
+        let mut return_points = BinaryHeap::<OrderId<E>>::with_capacity(skiplist_size);
         let dist_to_entry = self.distance(id, entry)?;
         let mut visited = FxHashSet::<u64>::default(); //HashSet::<u64>::new();
         visited.insert(entry.id());
-        let mut candidate = BinaryHeap::<OrderId<f32>>::with_capacity(skiplist_size);
-        candidate.push(entry.to_order_id(-dist_to_entry));
-        return_points.push(entry.to_order_id(dist_to_entry));
-        while !candidate.is_empty() {
-            let mut c = candidate.pop().unwrap();
-            let f = return_points.peek().unwrap();
-            if -(c.dist) > f.dist && return_points.len() >= ef {
-                return Ok(return_points);
-            }
-            let neighbor = self.get_neighbor(&mut c.point)?.read().unwrap().get(level);
+        let mut candidate = BinaryHeap::<OrderId<E>>::with_capacity(skiplist_size);
+        let entry_l = entry.at_level(level);
+        candidate.push(entry_l.to_order_id(-dist_to_entry));
+        if allowed(entry.id()) {
+            return_points.push(entry_l.to_order_id(dist_to_entry));
+        }
+        while let Some(mut c) = candidate.pop() {
+            if let Some(f) = return_points.peek() {
+                if -(c.dist) > f.dist && return_points.len() >= ef {
+                    return Ok(return_points);
+                }
+            }
+            let neighbor = match self.get_neighbor(&mut c.point) {
+                Ok(neighbor) => neighbor.read().unwrap().get(level),
+                Err(_) => continue,                             //已经被 vacuum 清理的点
+            };
             for mut n in neighbor {
                 if !visited.contains(&n.point.id()) {
                     visited.insert(n.point.id());
-                    let opt = return_points.peek();
-                    if opt.is_none() {
-                        return Ok(return_points);
-                    }
                     if let Ok(e_dist_to_p) = self.distance(id, &mut n.point) {
-                        let f_dist_to_p = opt.unwrap().dist;
+                        let f_dist_to_p = return_points.peek().map(|f| f.dist).unwrap_or(f32::MAX);
                         if e_dist_to_p < f_dist_to_p || return_points.len() < ef {
-                            let e_prime = n.point.to_order_id(e_dist_to_p);
                             candidate.push(n.point.to_order_id(-e_dist_to_p));
-                            return_points.push(e_prime);
-                            if return_points.len() > ef {
-                                return_points.pop();
+                            if allowed(n.point.id()) {
+                                return_points.push(n.point.to_order_id(e_dist_to_p));
+                                if return_points.len() > ef {
+                                    return_points.pop();
+                                }
                             }
                         }
                     }
@@ -140,14 +577,13 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
         Ok(return_points)
     }
 
-    fn select_neighbor(&self, id: &mut Point<f32>, candidates: &mut BinaryHeap<OrderId<f32>>, asked: usize, extend: bool) -> Result<Vec<OrderId<f32>>> {
+    fn select_neighbor(&self, id: &mut Point<E>, candidates: &mut BinaryHeap<OrderId<E>>, asked: usize, extend: bool) -> Result<Vec<OrderId<E>>> {
         let mut extend_candidates = false;
         let mut neighbor = Vec::new();
         if candidates.len() <= asked {
             if !extend {
                 while !candidates.is_empty() {
                     let p = candidates.pop().unwrap();
-                    assert!(-p.dist >= 0.);
                     neighbor.push(p.point.to_order_id(-p.dist));
                 }
                 return Ok(neighbor);
@@ -156,14 +592,14 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
             }
         }
         if extend_candidates {
-            let mut candidates_set = candidates.iter().fold(FxHashMap::<u64, Point<f32>>::default(), |mut set, c| {
+            let mut candidates_set = candidates.iter().fold(FxHashMap::<u64, Point<E>>::default(), |mut set, c| {
                 set.insert(c.point.id(), c.point.clone());
                 set
             });
-            let keys: FxHashSet<u64> = candidates_set.keys().map(|k| k.clone()).collect();
-            let mut new_candidates_set = FxHashMap::<u64, Point<f32>>::default();
-            for (_, mut cp) in &mut candidates_set {
-                let cp_neighbor = self.get_neighbor(&mut cp)?.read().unwrap().get(id.level());
+            let keys: FxHashSet<u64> = candidates_set.keys().copied().collect();
+            let mut new_candidates_set = FxHashMap::<u64, Point<E>>::default();
+            for cp in candidates_set.values_mut() {
+                let cp_neighbor = self.get_neighbor(cp)?.read().unwrap().get(id.level());
                 for p in cp_neighbor {
                     let pid = p.point.id();
                     if !new_candidates_set.contains_key(&pid) && !keys.contains(&pid) {
@@ -187,13 +623,13 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
         Ok(neighbor)
     }
 
-    fn reverse_update_neighbor(&self, point: &mut Point<f32>) -> Result<Vec<u64>> {
+    fn reverse_update_neighbor(&self, point: &mut Point<E>) -> Result<Vec<u64>> {
         let mut updated = vec![point.id()];
         let neighbor = self.get_neighbor(point)?.read().unwrap().clone();
         for mut n in neighbor.value {
             if n.point.level() <= point.level() && n.point.id() != point.id() {
-                let threshold = if point.level() > 0 { self.max_nb } else { 2 * self.max_nb };
-                if self.get_neighbor(&mut n.point)?.write().unwrap().push(point.to_order_id(n.dist), Some(threshold)) {
+                let threshold = if n.point.level() > 0 { self.max_nb } else { 2 * self.max_nb };
+                if self.get_neighbor(&mut n.point)?.write().unwrap().push(point.at_level(n.point.level()).to_order_id(n.dist), Some(threshold)) {
                     updated.push(n.point.id());
                 }
             }
@@ -203,48 +639,65 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
 
     fn save_neighbor(&self, id: u64) -> Result<()> {
         let nid = HNSW::<T>::get_id(b"N", id);
-        let buf = Bytes::from_owner(self.neighbors.get(&id).unwrap().read().unwrap().to_vec());
+        let neighbor = self.neighbors.read(&id, |_, v| v.clone()).ok_or(anyhow!("id {} not found", id))?;
+        let buf = Bytes::from_owner(neighbor.read().unwrap().to_vec());
         self.store.set(nid, buf)
     }
 
-    fn save_arrow(&self, id: u64) -> Result<()> {
-        let aid = HNSW::<T>::get_id(b"A", id);
-        let arrow = self.get_arrow(id)?.as_ref().clone();
-        self.store.set(aid, Bytes::from_owner(super::vec_to_u8(arrow)))
+    //磁盘上总是保存原始向量 内存里保存原始向量或者量化编码
+    fn save_arrow(&self, id: u64, arrow: Vec<E>) -> Result<()> {
+        let buf = Bytes::from_owner(super::vec_to_u8(&arrow));
+        match self.quantizer() {
+            Some(q) => {
+                let _ = q.codes.upsert(id, Arc::new(q.quantizer.encode(&arrow)));
+                self.arrows.remove(&id);
+            }
+            None => {
+                let _ = self.arrows.upsert(id, Arc::new(arrow));
+            }
+        }
+        self.store.set(HNSW::<T>::get_id(b"A", id), buf)
     }
 
-    pub fn insert(&self, arrow: Vec<f32>) -> Result<u64> {
+    pub fn insert(&self, arrow: Vec<E>) -> Result<u64> {
+        let arrow = self.dist_f.prepare(arrow)?;
+        self.check()?;
         let id = self.store.get_id();
         let _ = self.neighbors.insert(id, Arc::new(RwLock::new(LevelVec::default())));
-        let _ = self.arrows.insert(id, Arc::new(arrow));
-        self.save_arrow(id)?;
+        self.save_arrow(id, arrow)?;
         let updated = self.insert_id(id)?;
         for _id in updated {
             self.save_neighbor(_id)?;
         }
+        self.auto_train()?;
+        Ok(id)
+    }
+
+    pub fn insert_with_payload(&self, arrow: Vec<E>, payload: &Payload) -> Result<u64> {
+        let id = self.insert(arrow)?;
+        self.set_payload(id, payload)?;
         Ok(id)
     }
 
-    pub fn insert_batch(&self, arrows: Vec<Vec<f32>>) -> Result<Vec<u64>> {
+    pub fn insert_batch(&self, arrows: Vec<Vec<E>>) -> Result<Vec<u64>> {
+        let arrows = arrows.into_iter().map(|arrow| self.dist_f.prepare(arrow)).collect::<Result<Vec<_>>>()?;
+        self.check()?;
         let modified = Arc::new(RwLock::new(FxHashSet::<u64>::default()));
         let ids: Vec<u64> = arrows
             .into_par_iter()
             .map(|arrow| {
                 let id = self.store.get_id();
                 let _ = self.neighbors.insert(id, Arc::new(RwLock::new(LevelVec::default())));
-                let _ = self.arrows.insert(id, Arc::new(arrow));
-                self.save_arrow(id).unwrap();
-                let _ = modified.write().map(|mut m| {
-                    for _id in self.insert_id(id).unwrap() {
-                        m.insert(_id);
-                    }
-                });
-                id
+                self.save_arrow(id, arrow)?;
+                let mut m = modified.write().unwrap();
+                m.extend(self.insert_id(id)?);                  //集合在插入过程中被删除时 返回错误而不是 panic
+                Ok(id)
             })
-            .collect();
+            .collect::<Result<Vec<u64>>>()?;
         for _id in modified.read().unwrap().clone() {
             self.save_neighbor(_id)?;
         }
+        self.auto_train()?;
         Ok(ids)
     }
 
@@ -253,12 +706,17 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
             return Ok(vec![0]);
         }
         let level = self.layer_g.lock().unwrap().generate();
-        let mut id = Point::new(id, level);
         let (max_level_observed, entry) = self.store.entry();
+        self.link(id, level, max_level_observed, entry)
+    }
+
+    //把 id 连接到 level 层以下 从 entry 开始搜索 返回邻居有变化的 id
+    fn link(&self, id: u64, level: usize, max_level_observed: usize, entry: u64) -> Result<Vec<u64>> {
+        let mut id = Point::new(id, level);
         let mut entry = Point::new(entry, level);
         let mut dist_to_entry = self.distance(&mut id, &mut entry)?;
         for l in ((level + 1)..(max_level_observed + 1)).rev() {
-            let mut sorted_points = self.search_layer(&mut id, &mut entry, 1, l)?;
+            let mut sorted_points = self.search_layer(&mut id, &mut entry, 1, l, None)?;
             if let Some(mut ep) = sorted_points.pop() {
                 let tmp_dist = self.distance(&mut id, &mut ep.point)?;
                 if tmp_dist < dist_to_entry {
@@ -270,52 +728,877 @@ impl<T: KVStore + Clone + Send + Sync> HNSW<T> {
         }
         for l in (0..level + 1).rev() {
             let ef = self.ef;
-            let sorted_points = self.search_layer(&mut id, &mut entry, ef, l)?;
-            let mut sorted_points: BinaryHeap<OrderId<f32>> = sorted_points.into_iter().map(|p| p.point.to_order_id(-p.dist)).collect();
+            let sorted_points = self.search_layer(&mut id, &mut entry, ef, l, None)?;
+            let mut sorted_points: BinaryHeap<OrderId<E>> = sorted_points.into_iter().map(|p| p.point.to_order_id(-p.dist)).collect();
             if !sorted_points.is_empty() {
                 let mut nb_conn = self.max_nb;
                 let mut extend_c = false;
                 if l == 0 {
-                    nb_conn = 2 * nb_conn;
+                    nb_conn *= 2;
                     extend_c = true;
                 }
                 let mut neighbor = self.select_neighbor(&mut id, &mut sorted_points, nb_conn, extend_c)?;
                 neighbor.sort();
-                if neighbor.len() > 0 {
+                if !neighbor.is_empty() {
                     entry = neighbor[0].point.clone();
                 }
                 self.get_neighbor(&mut id)?.write().unwrap().append(&mut neighbor);
             }
         }
         let updated = self.reverse_update_neighbor(&mut id)?;
+        if level > 0 {
+            self.store.set(HNSW::<T>::get_id(b"L", id.id()), Bytes::copy_from_slice(&(level as u64).to_le_bytes()))?;
+        } else {
+            self.store.remove(HNSW::<T>::get_id(b"L", id.id()))?;
+        }
         self.store.set_entry(level, id.id());
         Ok(updated)
     }
 
-    pub fn search(&self, data: Vec<f32>, number: usize) -> Result<Vec<(u64, f32)>> {
+    //从入口点 逐层向下找到第 0 层的入口
+    fn search_entry(&self, qid: &mut Point<E>) -> Result<Point<E>> {
+        let (mut level, mut pivot) = self.store.entry();
+        let mut pivot_id = loop {
+            let mut point = Point::new(pivot, level);
+            match self.distance(qid, &mut point) {
+                Ok(dist) => break point.to_order_id(dist),
+                Err(e) => {
+                    let (l, p) = self.store.entry();         //入口点可能刚刚被 vacuum 替换
+                    if p == pivot {
+                        return Err(e);
+                    }
+                    (level, pivot) = (l, p);
+                }
+            }
+        };
+        for level in (1..=level).rev() {
+            let neighbor = match self.get_neighbor(&mut pivot_id.point) {
+                Ok(neighbor) => neighbor.read().unwrap().get(level),
+                Err(_) => continue,
+            };
+            for mut n in neighbor {
+                if let Ok(tmp_dist) = self.distance(qid, &mut n.point) {
+                    if tmp_dist < pivot_id.dist {
+                        pivot_id = n.point.to_order_id(tmp_dist);
+                    }
+                }
+            }
+        }
+        Ok(pivot_id.point)
+    }
+
+    fn search_knn(&self, data: Vec<E>, number: usize, ef: usize, filter: Option<&dyn Fn(u64) -> bool>) -> Result<Vec<OrderId<E>>> {
+        self.check()?;
         if self.store.size() == 0 {
             return Ok(Vec::new());
         }
-        let (level, pivot) = self.store.entry();
-        let mut pivot = Point::new(pivot, level);
-        let mut qid = Point::new(self.query_id.get(), level);
+        let mut qid = Point::new(self.query_id.get(), 0);
         qid.arrow = Some(Arc::new(data));
-        let dist = self.distance(&mut qid, &mut pivot)?;
-        let mut pivot_id = pivot.to_order_id(dist);
-        for level in (1..=level).rev() {
-            let neighbor = self.get_neighbor(&mut pivot_id.point)?.read().unwrap().get(level);
+        let mut pivot = self.search_entry(&mut qid)?;
+        let ef = ef.max(number);
+        let allowed = |id: u64| !self.deleted.contains(&id) && filter.map(|f| f(id)).unwrap_or(true);
+        let neighbors_heap = self.search_layer(&mut qid, &mut pivot, ef, 0, Some(&allowed))?;
+        let mut neighbors = neighbors_heap.into_sorted_vec();
+        if self.is_quantized() {
+            neighbors.truncate((number * RESCORE_FACTOR).min(ef));
+            neighbors = self.rescore(qid.arrow.as_ref().unwrap(), neighbors)?;
+        }
+        neighbors.truncate(number.min(ef));
+        Ok(neighbors)
+    }
+
+    //用磁盘上的原始向量重新计算距离 返回的点带着原始向量
+    fn rescore(&self, data: &[E], points: Vec<OrderId<E>>) -> Result<Vec<OrderId<E>>> {
+        let mut rescored = Vec::with_capacity(points.len());
+        for p in points {
+            if let Some(arrow) = self.load_arrow(p.point.id())? {
+                let mut point = p.point;
+                let dist = E::distance(&self.dist_f, data, &arrow);
+                point.arrow = Some(arrow);
+                rescored.push(point.to_order_id(dist));
+            }
+        }
+        rescored.sort();
+        Ok(rescored)
+    }
+
+    //清理已经删除的点 把指向它们的邻居重新连接 然后删除它们的 N 和 A 墓碑 D 保留
+    //先重连再删除 正在进行的搜索最多是跳过这些点
+    pub fn vacuum(&self) -> Result<usize> {
+        self.check()?;
+        let _lock = self.vacuum_lock.lock().unwrap();
+        let mut nodes = Vec::new();
+        for kv in self.store.scan(Bytes::from_static(b"N")) {
+            nodes.push(u64::from_le_bytes(kv?.0[1..].try_into()?));
+        }
+        if self.deleted.is_empty() {
+            return Ok(0);
+        }
+        let mut purge: FxHashSet<u64> = nodes.iter().filter(|id| self.deleted.contains(*id)).copied().collect();
+        //之前已经清理掉 (没有 N) 的点 也可能被上一次 vacuum 期间插入的点连接上 所有删除的点都要从邻居里去掉
+        let gone = |id: &u64| self.deleted.contains(id);
+        let mut top: Option<(usize, u64)> = None;                 //层数最高的活着的点 用作新的入口
+        for &id in nodes.iter().filter(|id| !purge.contains(id)) {
+            let neighbor = self.get_neighbor(&mut Point::new(id, 0))?;
+            let links = neighbor.read().unwrap().clone();
+            let level = self.node_level(id)?;
+            if top.map(|(l, _)| level > l).unwrap_or(true) {
+                top = Some((level, id));
+            }
+            let levels: FxHashSet<usize> = links.value.iter().filter(|n| gone(&n.point.id())).map(|n| n.point.level()).collect();
+            if levels.is_empty() {
+                continue;
+            }
+            for level in levels {
+                let mut candidates = FxHashMap::<u64, Point<E>>::default();
+                for n in links.get(level) {
+                    if gone(&n.point.id()) {
+                        let removed = self.get_neighbor(&mut n.point.clone()).map(|n| n.read().unwrap().get(level)).unwrap_or_default();
+                        for r in removed {
+                            if r.point.id() != id && !gone(&r.point.id()) {
+                                candidates.entry(r.point.id()).or_insert(r.point);
+                            }
+                        }
+                    } else {
+                        candidates.insert(n.point.id(), n.point);
+                    }
+                }
+                let mut point = Point::new(id, level);
+                let mut sorted_points = BinaryHeap::new();
+                for (_, mut c) in candidates {
+                    if let Ok(dist) = self.distance(&mut point, &mut c) {
+                        sorted_points.push(c.to_order_id(-dist));
+                    }
+                }
+                let nb_conn = if level == 0 { 2 * self.max_nb } else { self.max_nb };
+                let mut selected = self.select_neighbor(&mut point, &mut sorted_points, nb_conn, false)?;
+                let mut neighbor = neighbor.write().unwrap();
+                neighbor.value.retain(|n| n.point.level() != level);
+                neighbor.append(&mut selected);
+            }
+            self.save_neighbor(id)?;
+        }
+        let (_, entry) = self.store.entry();
+        if purge.contains(&entry) {
+            match top {
+                Some((level, id)) => self.store.replace_entry(level, id),
+                None => {
+                    purge.remove(&entry);                         //全部都删除了 保留入口点
+                }
+            }
+        }
+        for id in &purge {
+            self.store.remove(HNSW::<T>::get_id(b"N", *id))?;
+            self.store.remove(HNSW::<T>::get_id(b"A", *id))?;
+            self.store.remove(HNSW::<T>::get_id(b"L", *id))?;
+            self.neighbors.remove(id);
+            self.arrows.remove(id);
+            if let Some(q) = self.quantizer() {
+                q.codes.remove(id);
+            }
+        }
+        Ok(purge.len())
+    }
+
+    pub fn search(&self, data: Vec<E>, number: usize) -> Result<Vec<(u64, f32)>> {
+        self.search_with(data, &SearchParams::new(number))
+    }
+
+    pub fn search_filter(&self, data: Vec<E>, number: usize, filter: &Filter) -> Result<Vec<(u64, f32)>> {
+        self.search_with(data, &SearchParams::new(number).filter(filter.clone()))
+    }
+
+    pub fn search_with(&self, data: Vec<E>, params: &SearchParams) -> Result<Vec<(u64, f32)>> {
+        Ok(self.search_points(data, params, None)?.into_iter().map(|p| (p.point.id(), p.dist)).collect())
+    }
+
+    //分页 offset 和 search_after 都是先取到足够多的结果 再跳过前面的页
+    //候选堆的大小至少是 ef 所以 offset + k 不超过 ef 的各页 用的是同一次遍历 结果是一致的
+    //search_after 不知道前面有多少个结果 从 ef 个开始 不够时加倍重新搜索
+    fn search_points(&self, data: Vec<E>, params: &SearchParams, payloads: Option<&RefCell<FxHashMap<u64, Payload>>>) -> Result<Vec<OrderId<E>>> {
+        let data = self.dist_f.prepare(data)?;
+        let ef = params.ef.unwrap_or(self.ef);
+        let (last_dist, last_id) = match params.after {
+            Some(after) => after,
+            None => {
+                let result = self.search_page(&data, params.offset + params.k, ef, params, payloads)?;
+                return Ok(result.into_iter().skip(params.offset).take(params.k).collect());
+            }
+        };
+        let mut number = ef.max(params.k);
+        loop {
+            let result = self.search_page(&data, number, ef, params, payloads)?;
+            let full = result.len() >= number;
+            let result: Vec<OrderId<E>> = result.into_iter().filter(|p| p.dist > last_dist || (p.dist == last_dist && p.point.id() > last_id)).collect();
+            if result.len() >= params.k || !full {
+                return Ok(result.into_iter().take(params.k).collect());
+            }
+            number *= 2;
+        }
+    }
+
+    //返回的点带着遍历时已经加载的向量 payloads 不为空时 过滤条件读取过的 payload 会留在里面
+    fn search_page(&self, data: &[E], number: usize, ef: usize, params: &SearchParams, payloads: Option<&RefCell<FxHashMap<u64, Payload>>>) -> Result<Vec<OrderId<E>>> {
+        let flat = self.flat_threshold > 0 && self.len() <= self.flat_threshold;     //数据量小的时候 直接暴力搜索
+        let knn = |filter: Option<&dyn Fn(u64) -> bool>| {
+            if flat { self.exact_knn(data, number, filter) }
+            else { self.search_knn(data.to_vec(), number, ef.max(number), filter) }
+        };
+        let mut result = match &params.filter {
+            Some(filter) => {
+                let matches = |id: u64| match self.load_payload(id).ok().flatten() {
+                    Some(p) if filter.matches(&p) => {
+                        if let Some(payloads) = payloads {
+                            payloads.borrow_mut().insert(id, p);
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+                match self.index_ids(filter)? {
+                    Some((ids, exact)) => {
+                        let allowed = |id: u64| ids.contains(&id) && (exact || matches(id));
+                        knn(Some(&allowed))?
+                    }
+                    None => knn(Some(&matches))?,
+                }
+            }
+            None => knn(None)?,
+        };
+        if let Some(max_distance) = params.max_distance {
+            result.retain(|p| p.dist <= max_distance);
+        }
+        Ok(result)
+    }
+
+    //和 search_with 一样 按照 options 附带向量和 payload
+    pub fn search_hits(&self, data: Vec<E>, params: &SearchParams, options: &SearchOptions) -> Result<Vec<Hit<E>>> {
+        let payloads = RefCell::new(FxHashMap::default());
+        let points = self.search_points(data, params, options.with_payload.then_some(&payloads))?;
+        let mut payloads = payloads.into_inner();
+        points
+            .into_iter()
+            .map(|p| {
+                let id = p.point.id();
+                let vector = match (options.with_vector, p.point.arrow) {
+                    (true, Some(arrow)) => Some(arrow.as_ref().clone()),
+                    (true, None) => self.load_arrow(id)?.map(|arrow| arrow.as_ref().clone()),
+                    _ => None,
+                };
+                let payload = match (options.with_payload, payloads.remove(&id)) {
+                    (true, Some(payload)) => Some(payload),
+                    (true, None) => self.load_payload(id)?,
+                    _ => None,
+                };
+                Ok(Hit { id, dist: p.dist, vector, payload })
+            })
+            .collect()
+    }
+
+    //MMR 多样化搜索 先取 ef 和 4k 中较大的数量作为候选 然后每次选出 lambda * 和查询的相似度 - (1 - lambda) * 和已选结果的最大相似度 最大的点
+    //相似度用距离的相反数 lambda 为 1 时等同于普通搜索 返回的仍然是和查询的距离
+    pub fn search_mmr(&self, data: Vec<E>, params: &SearchParams, lambda: f32) -> Result<Vec<(u64, f32)>> {
+        if !(0. ..=1.).contains(&lambda) {
+            return Err(anyhow!("lambda {} is not in [0, 1]", lambda));
+        }
+        let data = self.dist_f.prepare(data)?;
+        let ef = params.ef.unwrap_or(self.ef);
+        let mut candidates = self.search_page(&data, ef.max(4 * params.k), ef, params, None)?;
+        let mut arrows = Vec::with_capacity(candidates.len());
+        for c in &candidates {
+            arrows.push(match &c.point.arrow {
+                Some(arrow) => arrow.clone(),
+                None => self.get_arrow(c.point.id())?,
+            });
+        }
+        let mut min_dist = vec![f32::MAX; candidates.len()];            //每个候选到已选结果的最小距离
+        let mut selected = Vec::with_capacity(params.k);
+        while selected.len() < params.k && !candidates.is_empty() {
+            let score = |i: usize| -lambda * candidates[i].dist + if selected.is_empty() { 0. } else { (1. - lambda) * min_dist[i] };
+            let best = (0..candidates.len()).max_by(|&a, &b| score(a).total_cmp(&score(b)).then(b.cmp(&a))).unwrap();
+            let picked = candidates.swap_remove(best);
+            let arrow = arrows.swap_remove(best);
+            min_dist.swap_remove(best);
+            for (i, other) in arrows.iter().enumerate() {
+                min_dist[i] = min_dist[i].min(E::distance(&self.dist_f, &arrow, other));
+            }
+            selected.push((picked.point.id(), picked.dist));
+        }
+        Ok(selected)
+    }
+
+    //用集合里已有的向量作为正例和负例 推荐相似的向量 正例和负例本身不会返回
+    pub fn recommend(&self, positive: &[u64], negative: &[u64], k: usize, strategy: Strategy) -> Result<Vec<(u64, f32)>> {
+        self.check()?;
+        if positive.is_empty() {
+            return Err(anyhow!("recommend need at least one positive example"));
+        }
+        let examples = |ids: &[u64]| ids.iter().map(|id| if self.deleted.contains(id) { Err(anyhow!("id {} not found", id)) } else { self.get_arrow(*id) }).collect::<Result<Vec<_>>>();
+        let (pos, neg) = (examples(positive)?, examples(negative)?);
+        let allowed = |id: u64| !positive.contains(&id) && !negative.contains(&id);
+        let params = SearchParams::new(k);
+        match strategy {
+            Strategy::AverageVector if E::TYPE == ElementType::Binary => Err(anyhow!("AverageVector is not supported for binary vectors, use BestScore")),
+            Strategy::AverageVector => {
+                let average = |arrows: &[Arc<Vec<E>>]| {
+                    let mut sum = vec![0f32; arrows[0].len()];
+                    for arrow in arrows {
+                        sum.iter_mut().zip(arrow.iter()).for_each(|(s, v)| *s += v.to_f32() / arrows.len() as f32);
+                    }
+                    sum
+                };
+                let mut query = average(&pos);
+                if !neg.is_empty() {
+                    let neg = average(&neg);
+                    query.iter_mut().zip(neg).for_each(|(q, n)| *q += *q - n);
+                }
+                let query = self.dist_f.prepare(query.into_iter().map(E::from_f32).collect())?;
+                let ef = self.ef.max(k);
+                let flat = self.flat_threshold > 0 && self.len() <= self.flat_threshold;
+                let result = if flat { self.exact_knn(&query, k, Some(&allowed))? } else { self.search_knn(query, k, ef, Some(&allowed))? };
+                Ok(result.into_iter().map(|p| (p.point.id(), p.dist)).collect())
+            }
+            Strategy::BestScore => {
+                let mut candidates = FxHashMap::<u64, Arc<Vec<E>>>::default();
+                for arrow in &pos {
+                    for p in self.search_page(arrow, self.ef.max(k + positive.len() + negative.len()), self.ef, &params, None)? {
+                        let id = p.point.id();
+                        if allowed(id) {
+                            candidates.insert(id, match p.point.arrow { Some(arrow) => arrow, None => self.get_arrow(id)? });
+                        }
+                    }
+                }
+                let nearest = |examples: &[Arc<Vec<E>>], arrow: &[E]| examples.iter().map(|e| E::distance(&self.dist_f, e, arrow)).fold(f32::MAX, f32::min);
+                let mut result: Vec<(u64, f32, f32)> = candidates.into_iter().map(|(id, arrow)| (id, nearest(&pos, &arrow), nearest(&neg, &arrow))).collect();
+                //离正例更近的 按照到正例的距离排在前面 其余的 离负例越远越靠前
+                result.sort_by(|a, b| (a.2 < a.1).cmp(&(b.2 < b.1)).then_with(|| if a.2 < a.1 { b.2.total_cmp(&a.2) } else { a.1.total_cmp(&b.1) }).then(a.0.cmp(&b.0)));
+                Ok(result.into_iter().take(k).map(|(id, dist, _)| (id, dist)).collect())
+            }
+        }
+    }
+
+    //扫描所有存储的向量 得到精确的结果
+    fn exact_knn(&self, data: &[E], number: usize, filter: Option<&dyn Fn(u64) -> bool>) -> Result<Vec<OrderId<E>>> {
+        self.check()?;
+        let mut heap = BinaryHeap::<OrderId<E>>::with_capacity(number + 1);
+        for kv in self.store.scan(Bytes::from_static(b"A")) {
+            let (key, value) = kv?;
+            let id = u64::from_le_bytes(key[1..].try_into()?);
+            if self.deleted.contains(&id) || !filter.map(|f| f(id)).unwrap_or(true) {
+                continue;
+            }
+            let arrow = self.arrows.read(&id, |_, v| v.clone()).unwrap_or_else(|| Arc::new(super::u8_to_vec(&value)));
+            let mut point = Point::new(id, 0);
+            let dist = E::distance(&self.dist_f, data, &arrow);
+            point.arrow = Some(arrow);
+            heap.push(point.to_order_id(dist));
+            if heap.len() > number {
+                heap.pop();
+            }
+        }
+        Ok(heap.into_sorted_vec())
+    }
+
+    pub fn search_exact(&self, data: Vec<E>, number: usize) -> Result<Vec<(u64, f32)>> {
+        let data = self.dist_f.prepare(data)?;
+        Ok(self.exact_knn(&data, number, None)?.into_iter().map(|p| (p.point.id(), p.dist)).collect())
+    }
+
+    pub fn search_batch(&self, queries: Vec<Vec<E>>, number: usize) -> Result<Vec<Vec<(u64, f32)>>> {
+        self.search_batch_with(queries, &SearchParams::new(number), 0)
+    }
+
+    //并行查询 结果和输入的顺序一致 任何一个查询出错 整批返回错误 threads 为 0 时使用 rayon 的全局线程池
+    pub fn search_batch_with(&self, queries: Vec<Vec<E>>, params: &SearchParams, threads: usize) -> Result<Vec<Vec<(u64, f32)>>> {
+        self.check()?;
+        let run = || queries.into_par_iter().map(|data| self.search_with(data, params)).collect::<Result<Vec<_>>>();
+        if threads == 0 {
+            run()
+        } else {
+            rayon::ThreadPoolBuilder::new().num_threads(threads).build()?.install(run)
+        }
+    }
+
+    //距离不大于 radius 的所有向量 先用 search_layer 得到种子 然后只要最近的未扩展点还在半径以内就继续扩展 最多返回 limit 个
+    pub fn search_radius(&self, data: Vec<E>, radius: f32, limit: usize) -> Result<Vec<(u64, f32)>> {
+        self.check()?;
+        let data = self.dist_f.prepare(data)?;
+        if self.store.size() == 0 {
+            return Ok(Vec::new());
+        }
+        let mut qid = Point::new(self.query_id.get(), 0);
+        qid.arrow = Some(Arc::new(data));
+        let mut pivot = self.search_entry(&mut qid)?;
+        let seeds = self.search_layer(&mut qid, &mut pivot, self.ef, 0, None)?;
+        let mut visited: FxHashSet<u64> = seeds.iter().map(|s| s.point.id()).collect();
+        let mut result: Vec<(u64, f32)> = seeds.iter().filter(|s| s.dist <= radius && !self.deleted.contains(&s.point.id())).map(|s| (s.point.id(), s.dist)).collect();
+        let mut candidate: BinaryHeap<OrderId<E>> = seeds.into_iter().map(|s| s.point.to_order_id(-s.dist)).collect();
+        while let Some(mut c) = candidate.pop() {
+            if -c.dist > radius || result.len() >= limit {
+                break;
+            }
+            let neighbor = match self.get_neighbor(&mut c.point) {
+                Ok(neighbor) => neighbor.read().unwrap().get(0),
+                Err(_) => continue,
+            };
             for mut n in neighbor {
-                let tmp_dist = self.distance(&mut qid, &mut n.point)?;
-                if tmp_dist < pivot_id.dist {
-                    pivot_id = n.point.to_order_id(tmp_dist);
+                if visited.insert(n.point.id()) {
+                    if let Ok(dist) = self.distance(&mut qid, &mut n.point) {
+                        if dist <= radius {
+                            if !self.deleted.contains(&n.point.id()) {
+                                result.push((n.point.id(), dist));
+                            }
+                            candidate.push(n.point.to_order_id(-dist));
+                        }
+                    }
                 }
             }
         }
-        let ef = self.ef.max(number);
-        let neighbors_heap = self.search_layer(&mut qid, &mut pivot, ef, 0)?;
-        let mut neighbors = neighbors_heap.into_sorted_vec();
-        neighbors.truncate(number.min(ef));
-        let ids: Vec<(u64, f32)> = neighbors.into_iter().map(|p| (p.point.id(), p.dist)).collect();
-        Ok(ids)
+        if self.is_quantized() {                           //量化距离只是近似值 用原始向量重新判断半径
+            let data = qid.arrow.as_ref().unwrap();
+            let mut rescored = Vec::with_capacity(result.len());
+            for (id, _) in result {
+                if let Some(arrow) = self.load_arrow(id)? {
+                    let dist = E::distance(&self.dist_f, data, &arrow);
+                    if dist <= radius {
+                        rescored.push((id, dist));
+                    }
+                }
+            }
+            result = rescored;
+        }
+        result.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        result.truncate(limit);
+        Ok(result)
+    }
+
+    pub fn search_with_key(&self, data: Vec<E>, number: usize) -> Result<Vec<(u64, f32, Option<Key>)>> {
+        self.search(data, number)?.into_iter().map(|(id, dist)| Ok((id, dist, self.key_of(id)?))).collect()
+    }
+
+    pub fn search_with_payload(&self, data: Vec<E>, number: usize) -> Result<Vec<(u64, f32, Option<Payload>)>> {
+        self.search(data, number)?.into_iter().map(|(id, dist)| Ok((id, dist, self.get_payload(id)?))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HNSW;
+    use crate::db::order_id::Point;
+    use crate::db::{Dist, PersistID};
+    use crate::db::filter::Filter;
+    use crate::db::key::Key;
+    use crate::db::payload::{payload, Value};
+    use crate::db::search::{SearchOptions, SearchParams, Strategy};
+    use crate::store::fjall::FjallStore;
+    use crate::store::KVStore;
+    use fjall::Config;
+    use rustc_hash::{FxHashMap, FxHashSet};
+
+    fn open(name: &str) -> HNSW<FjallStore> {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        let space = Config::new(path).open_transactional().unwrap();
+        HNSW::new(FjallStore::open(&space, "test"), 20, 200, 16, Dist::L2)
+    }
+
+    #[test]
+    fn test_get() {
+        let hnsw = open("arrowdb_test_get");
+        let ids = hnsw.insert_batch((0..10).map(|i| vec![i as f32, 1.]).collect()).unwrap();
+        assert_eq!(hnsw.get(ids[3]).unwrap(), Some(vec![3., 1.]));
+        hnsw.arrows.clear();
+        assert_eq!(hnsw.get(ids[5]).unwrap(), Some(vec![5., 1.]));
+        assert_eq!(hnsw.get(100).unwrap(), None);
+        assert_eq!(hnsw.get_many(&[ids[1], 100, ids[2]]).unwrap(), vec![Some(vec![1., 1.]), None, Some(vec![2., 1.])]);
+    }
+
+    #[test]
+    fn test_payload() {
+        let hnsw = open("arrowdb_test_payload");
+        let doc = payload([("title", "hello".into()), ("year", 2024.into()), ("score", 0.5.into()), ("tags", vec!["a", "b"].into())]);
+        let id = hnsw.insert_with_payload(vec![1., 0.], &doc).unwrap();
+        let other = hnsw.insert(vec![0., 1.]).unwrap();
+        assert_eq!(hnsw.get_payload(id).unwrap(), Some(doc.clone()));
+        assert_eq!(hnsw.get_payload(other).unwrap(), None);
+        assert!(hnsw.set_payload(100, &doc).is_err());
+
+        let result = hnsw.search_with_payload(vec![1., 0.1], 2).unwrap();
+        assert_eq!(result[0].0, id);
+        assert_eq!(result[0].2.as_ref().and_then(|p| p.get("year")), Some(&Value::Int(2024)));
+        assert_eq!(result[1].2, None);
+
+        hnsw.delete_payload(id).unwrap();
+        assert_eq!(hnsw.get_payload(id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_search_filter() {
+        let hnsw = open("arrowdb_test_filter");
+        for i in 0..500 {
+            let tenant = if i % 50 == 0 { "rare" } else { "common" };
+            hnsw.insert_with_payload(vec![i as f32, 0.], &payload([("tenant", tenant.into()), ("i", (i as i64).into())])).unwrap();
+        }
+        let result = hnsw.search_filter(vec![0., 0.], 10, &Filter::eq("tenant", "rare")).unwrap();
+        assert_eq!(result.iter().map(|r| r.0).collect::<Vec<_>>(), (0..10).map(|i| i * 50).collect::<Vec<_>>());
+        let filter = Filter::range("i", 100.0..).and(Filter::eq("tenant", "rare").not());
+        let result = hnsw.search_filter(vec![0., 0.], 3, &filter).unwrap();
+        assert_eq!(result.iter().map(|r| r.0).collect::<Vec<_>>(), vec![101, 102, 103]);
+        assert!(hnsw.search_filter(vec![0., 0.], 3, &Filter::eq("tenant", "none")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_upsert() {
+        let hnsw = open("arrowdb_test_upsert");
+        let a = hnsw.upsert("a".into(), vec![1., 0.]).unwrap();
+        let b = hnsw.upsert(Key::Int(7), vec![0., 1.]).unwrap();
+        assert_eq!(hnsw.upsert("a".into(), vec![2., 0.]).unwrap(), a);
+        assert_eq!(hnsw.get(a).unwrap(), Some(vec![2., 0.]));
+        assert_eq!(hnsw.id_of(&Key::Int(7)).unwrap(), Some(b));
+        assert_eq!(hnsw.id_of(&"c".into()).unwrap(), None);
+        let result = hnsw.search_with_key(vec![0., 2.], 2).unwrap();
+        assert_eq!(result[0], (b, 1., Some(Key::Int(7))));
+        assert_eq!(result[1].2, Some("a".into()));
+        hnsw.remove(b).unwrap();
+        assert_eq!(hnsw.id_of(&Key::Int(7)).unwrap(), None);
+        assert_eq!(hnsw.key_of(b).unwrap(), None);
+
+        let upserts: Vec<_> = (0..8).map(|i| {
+            let hnsw = hnsw.clone();
+            std::thread::spawn(move || hnsw.upsert("same".into(), vec![i as f32, 3.]).unwrap())
+        }).collect();
+        let ids: FxHashSet<u64> = upserts.into_iter().map(|u| u.join().unwrap()).collect();
+        assert_eq!(ids.len(), 1);                               //同一个 key 只会插入一次
+        assert!(hnsw.upserting.is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let hnsw = open("arrowdb_test_remove");
+        let ids = hnsw.insert_batch((0..50).map(|i| vec![i as f32, 0.]).collect()).unwrap();
+        let (_, entry) = hnsw.store.entry();
+        let victim = if ids[10] == entry { ids[11] } else { ids[10] };
+        hnsw.remove(entry).unwrap();
+        hnsw.remove(victim).unwrap();
+        assert!(hnsw.remove(victim).is_err());
+        assert_eq!(hnsw.len(), 48);
+        assert_eq!(hnsw.get(victim).unwrap(), None);
+        let result = hnsw.search(vec![10., 0.], 3).unwrap();
+        assert!(result.iter().all(|r| r.0 != victim && r.0 != entry));
+        assert_eq!(result.len(), 3);
+
+        let hnsw = HNSW::new(hnsw.store.clone(), 20, 200, 16, Dist::L2);
+        assert_eq!(hnsw.len(), 48);
+        assert!(hnsw.is_deleted(victim));
+        assert!(hnsw.search(vec![10., 0.], 50).unwrap().iter().all(|r| r.0 != victim && r.0 != entry));
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let hnsw = open("arrowdb_test_vacuum");
+        let ids = hnsw.insert_batch((0..200).map(|i| vec![(i % 20) as f32, (i / 20) as f32]).collect()).unwrap();
+        let (_, entry) = hnsw.store.entry();
+        let removed: Vec<u64> = ids.iter().copied().filter(|id| id % 3 == 0 || *id == entry).collect();
+        for id in &removed {
+            hnsw.remove(*id).unwrap();
+        }
+        assert_eq!(hnsw.vacuum().unwrap(), removed.len());
+        assert_eq!(hnsw.vacuum().unwrap(), 0);
+        let (level, new_entry) = hnsw.store.entry();
+        assert_ne!(new_entry, entry);
+        assert_eq!(level, hnsw.node_level(new_entry).unwrap());       //新的入口点确实在这一层
+        assert_eq!(hnsw.len(), 200 - removed.len());
+
+        let hnsw = HNSW::new(hnsw.store.clone(), 20, 200, 16, Dist::L2);
+        for (i, id) in ids.iter().enumerate() {
+            let query = vec![(i % 20) as f32, (i / 20) as f32];
+            let result = hnsw.search(query, 1).unwrap();
+            if removed.contains(id) {
+                assert!(result[0].1 > 0.);
+            } else {
+                assert_eq!(result[0].0, *id);
+            }
+        }
+        hnsw.insert(vec![0.5, 0.5]).unwrap();
+        assert_eq!(hnsw.search(vec![0.5, 0.5], 1).unwrap()[0].1, 0.);
+    }
+
+    #[test]
+    fn test_vacuum_stale_edge() {
+        let hnsw = open("arrowdb_test_vacuum_stale");
+        let ids = hnsw.insert_batch((0..100).map(|i| vec![(i % 10) as f32, (i / 10) as f32]).collect()).unwrap();
+        let (_, entry) = hnsw.store.entry();
+        let stale = if ids[55] == entry { ids[56] } else { ids[55] };
+        hnsw.remove(stale).unwrap();
+        hnsw.store.remove(HNSW::<FjallStore>::get_id(b"N", stale)).unwrap();     //上一次 vacuum 已经清理掉 但是还有点连着它
+        hnsw.neighbors.remove(&stale);
+        assert_eq!(hnsw.vacuum().unwrap(), 0);
+        for id in ids.iter().filter(|id| **id != stale) {
+            let neighbor = hnsw.get_neighbor(&mut Point::new(*id, 0)).unwrap();
+            assert!(neighbor.read().unwrap().value.iter().all(|n| n.point.id() != stale));
+        }
+        hnsw.neighbors.clear();
+        assert!(hnsw.search(vec![5., 5.], 5).unwrap().iter().all(|r| r.0 != stale));
+    }
+
+    #[test]
+    fn test_vacuum_while_searching() {
+        let hnsw = open("arrowdb_test_vacuum_search");
+        let ids = hnsw.insert_batch((0..1000).map(|i| vec![(i % 40) as f32, (i / 40) as f32]).collect()).unwrap();
+        for id in ids.iter().filter(|id| *id % 2 == 0) {
+            hnsw.remove(*id).unwrap();
+        }
+        let searching: Vec<_> = (0..4).map(|t| {
+            let hnsw = hnsw.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    hnsw.search(vec![((i * 7 + t) % 40) as f32, ((i * 3) % 25) as f32], 5).unwrap();
+                }
+            })
+        }).collect();
+        assert_eq!(hnsw.vacuum().unwrap(), 500);
+        for s in searching {
+            s.join().unwrap();
+        }
+        assert!(hnsw.search(vec![1., 0.], 1).unwrap()[0].0 % 2 == 1);
+    }
+
+    #[test]
+    fn test_update() {
+        let hnsw = open("arrowdb_test_update");
+        let ids = hnsw.insert_batch((0..300).map(|i| vec![(i % 20) as f32, (i / 20) as f32]).collect()).unwrap();
+        let (_, entry) = hnsw.store.entry();
+        let moved = if ids[5] == entry { ids[6] } else { ids[5] };
+        hnsw.update(moved, vec![7.5, 7.5]).unwrap();      //移到网格中间 太远的孤立点可能因为邻居数量的限制而不可达
+        hnsw.update(entry, vec![-100., -100.]).unwrap();
+        for id in &ids {                                       //指向移动过的点的边 距离也更新了
+            let arrow = hnsw.get(*id).unwrap().unwrap();
+            let neighbor = hnsw.get_neighbor(&mut Point::new(*id, 0)).unwrap();
+            for n in neighbor.read().unwrap().value.iter().filter(|n| n.point.id() == moved || n.point.id() == entry) {
+                assert_eq!(n.dist, Dist::L2.eval(&arrow, &hnsw.get(n.point.id()).unwrap().unwrap()));
+            }
+        }
+        hnsw.neighbors.clear();
+        hnsw.arrows.clear();
+        assert_eq!(hnsw.get(moved).unwrap(), Some(vec![7.5, 7.5]));
+        assert_eq!(hnsw.search(vec![7.5, 7.5], 1).unwrap()[0], (moved, 0.));
+        assert_ne!(hnsw.search(vec![5., 0.], 1).unwrap()[0].0, moved);
+        assert_eq!(hnsw.search(vec![-99., -99.], 1).unwrap()[0].0, entry);
+        let probe = if ids[147] == entry { 148 } else { 147 };
+        assert_eq!(hnsw.search(vec![(probe % 20) as f32, (probe / 20) as f32], 1).unwrap()[0].0, ids[probe]);
+        assert!(hnsw.update(1000, vec![0., 0.]).is_err());
+    }
+
+    #[test]
+    fn test_update_keeps_level() {
+        let hnsw = open("arrowdb_test_update_level");
+        let mut seed = 11u64;
+        let mut rand = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 40) as f32 / (1u64 << 24) as f32
+        };
+        let ids = hnsw.insert_batch((0..500).map(|_| (0..8).map(|_| rand()).collect()).collect()).unwrap();
+        let upper = |hnsw: &HNSW<FjallStore>| {
+            let mut upper = std::collections::BTreeSet::new();
+            for id in &ids {
+                let neighbor = hnsw.get_neighbor(&mut Point::new(*id, 0)).unwrap();
+                upper.extend(neighbor.read().unwrap().value.iter().filter(|n| n.point.level() > 0).map(|n| n.point.id()));
+            }
+            upper
+        };
+        let levels: FxHashMap<u64, usize> = ids.iter().map(|id| (*id, hnsw.node_level(*id).unwrap())).collect();
+        let before = upper(&hnsw);
+        for id in &ids {
+            hnsw.update(*id, (0..8).map(|_| rand()).collect()).unwrap();
+        }
+        assert!(ids.iter().all(|id| hnsw.node_level(*id).unwrap() == levels[id]));
+        let after = upper(&hnsw);                           //上层的点只能是本来就在上层的点
+        assert!(after.iter().all(|id| levels[id] > 0 || before.contains(id)), "{} -> {}", before.len(), after.len());
+    }
+
+    #[test]
+    fn test_search_with() {
+        let hnsw = open("arrowdb_test_search_with");
+        hnsw.insert_batch((0..100).map(|i| vec![i as f32, 0.]).collect()).unwrap();
+        assert_eq!(hnsw.search_with(vec![0., 0.], &SearchParams::new(5).ef(1)).unwrap().len(), 5);
+        let result = hnsw.search_with(vec![50., 0.], &SearchParams::new(10).ef(64).max_distance(2.)).unwrap();
+        let mut ids: Vec<u64> = result.iter().map(|r| r.0).collect();
+        ids.sort();
+        assert_eq!(ids, vec![48, 49, 50, 51, 52]);
+    }
+
+    #[test]
+    fn test_search_radius() {
+        let hnsw = open("arrowdb_test_radius");
+        hnsw.insert_batch((0..400).map(|i| vec![(i % 20) as f32, (i / 20) as f32]).collect()).unwrap();
+        let result = hnsw.search_radius(vec![10., 10.], 3., 1000).unwrap();
+        assert_eq!(result.len(), 29);
+        assert!(result.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(result.iter().all(|r| r.1 <= 3.));
+        assert_eq!(hnsw.search_radius(vec![10., 10.], 3., 10).unwrap().len(), 10);
+        assert!(hnsw.search_radius(vec![100., 100.], 3., 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_batch() {
+        let hnsw = open("arrowdb_test_batch");
+        hnsw.insert_batch((0..200).map(|i| vec![i as f32, 0.]).collect()).unwrap();
+        let queries: Vec<Vec<f32>> = (0..50).map(|i| vec![(i * 4) as f32, 0.1]).collect();
+        let result = hnsw.search_batch(queries.clone(), 1).unwrap();
+        assert_eq!(result.iter().map(|r| r[0].0).collect::<Vec<_>>(), (0..50).map(|i| i * 4).collect::<Vec<_>>());
+        let result = hnsw.search_batch_with(queries, &SearchParams::new(3), 2).unwrap();
+        assert!(result.iter().all(|r| r.len() == 3));
+    }
+
+    #[test]
+    fn test_search_exact() {
+        let hnsw = open("arrowdb_test_exact").with_flat_threshold(100);
+        let ids = hnsw.insert_batch((0..100).map(|i| vec![i as f32, 0.]).collect()).unwrap();
+        hnsw.remove(ids[30]).unwrap();
+        let result = hnsw.search_exact(vec![30.2, 0.], 3).unwrap();
+        assert_eq!(result.iter().map(|r| r.0).collect::<Vec<_>>(), vec![ids[31], ids[29], ids[32]]);
+        assert_eq!(hnsw.search(vec![30.2, 0.], 3).unwrap(), result);
+    }
+
+    #[test]
+    fn test_search_hits() {
+        let hnsw = open("arrowdb_test_hits");
+        let ids = hnsw.insert_batch((0..50).map(|i| vec![i as f32, 1.]).collect()).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            hnsw.set_payload(*id, &payload([("i", (i as i64).into()), ("even", (i % 2 == 0).into())])).unwrap();
+        }
+        let params = SearchParams::new(2).filter(Filter::eq("even", true));
+        let hits = hnsw.search_hits(vec![10.2, 1.], &params, &SearchOptions::new().with_vector().with_payload()).unwrap();
+        assert_eq!(hits.iter().map(|h| h.id).collect::<Vec<_>>(), vec![ids[10], ids[12]]);
+        assert_eq!(hits[0].vector, Some(vec![10., 1.]));
+        assert_eq!(hits[1].payload.as_ref().and_then(|p| p.get("i")), Some(&Value::Int(12)));
+        let hits = hnsw.search_hits(vec![10.2, 1.], &SearchParams::new(1), &SearchOptions::new().with_payload()).unwrap();
+        assert_eq!((hits[0].id, hits[0].vector.is_none()), (ids[10], true));
+        assert!(hits[0].payload.is_some());
+    }
+
+    #[test]
+    fn test_pagination() {
+        let hnsw = open("arrowdb_test_pagination");
+        let ids = hnsw.insert_batch((0..300).map(|i| vec![(i / 2) as f32, 0.]).collect()).unwrap();  //每个距离都有两个点
+        let all = hnsw.search_exact(vec![0., 0.], 300).unwrap();
+        for w in all.windows(2) {
+            assert!(w[0].1 < w[1].1 || (w[0].1 == w[1].1 && w[0].0 < w[1].0));
+        }
+        assert_eq!(hnsw.search_with(vec![0., 0.], &SearchParams::new(20).offset(40)).unwrap(), all[40..60].to_vec());
+        let mut pages = Vec::new();
+        let mut params = SearchParams::new(20);
+        loop {
+            let page = hnsw.search_with(vec![0., 0.], &params).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            let (id, dist) = page[page.len() - 1];
+            params = SearchParams::new(20).search_after(dist, id);
+            pages.extend(page);
+        }
+        assert_eq!(pages.len(), ids.len());
+        assert_eq!(pages, all);
+    }
+
+    #[test]
+    fn test_search_mmr() {
+        let hnsw = open("arrowdb_test_mmr");
+        let mut arrows = Vec::new();
+        for center in [[1., 0.], [0., 1.2], [-1.4, 0.]] {                //三组几乎重复的向量
+            for i in 0..5 {
+                arrows.push(vec![center[0], center[1] + i as f32 * 0.01]);
+            }
+        }
+        arrows.push(vec![10., 10.]);
+        let ids = hnsw.insert_batch(arrows).unwrap();
+        let group = |id: u64| ids.iter().position(|i| *i == id).unwrap() / 5;
+        let result = hnsw.search_mmr(vec![0., 0.], &SearchParams::new(3), 1.).unwrap();
+        assert_eq!(result, hnsw.search(vec![0., 0.], 3).unwrap());
+        assert!(result.iter().all(|r| group(r.0) == 0));
+        let result = hnsw.search_mmr(vec![0., 0.], &SearchParams::new(3), 0.5).unwrap();
+        assert_eq!(result.iter().map(|r| group(r.0)).collect::<Vec<_>>(), vec![0, 2, 1]);
+        assert_eq!(result[0], (ids[0], 1.));
+        assert!((result[1].1 - 1.4).abs() < 1e-5);
+        assert!(hnsw.search_mmr(vec![0., 0.], &SearchParams::new(3), 1.5).is_err());
+    }
+
+    #[test]
+    fn test_recommend() {
+        let hnsw = open("arrowdb_test_recommend");
+        let ids = hnsw.insert_batch((0..100).map(|i| vec![(i % 10) as f32, (i / 10) as f32]).collect()).unwrap();
+        let result = hnsw.recommend(&[ids[0], ids[2]], &[], 3, Strategy::AverageVector).unwrap();
+        assert_eq!(result[0], (ids[1], 0.));
+        assert!(result.iter().all(|r| r.0 != ids[0] && r.0 != ids[2]));
+        let result = hnsw.recommend(&[ids[55]], &[ids[54]], 1, Strategy::AverageVector).unwrap();
+        assert_eq!(result[0].0, ids[56]);                                //5.5 + (5.5 - 4.5) = 6.5
+        let result = hnsw.recommend(&[ids[0], ids[99]], &[ids[98]], 4, Strategy::BestScore).unwrap();
+        assert_eq!(result.len(), 4);
+        assert_eq!(&result[..2].iter().map(|r| r.1).collect::<Vec<_>>(), &[1., 1.]);
+        assert!([ids[1], ids[10]].contains(&result[0].0) && [ids[1], ids[10]].contains(&result[1].0));
+        assert!(result.iter().all(|r| ![ids[0], ids[99], ids[98]].contains(&r.0)));
+        assert!(hnsw.recommend(&[], &[ids[1]], 3, Strategy::BestScore).is_err());
+        assert!(hnsw.recommend(&[1000], &[], 3, Strategy::BestScore).is_err());
+    }
+
+    #[test]
+    fn test_dot_distance() {
+        let path = std::env::temp_dir().join("arrowdb_test_dot");
+        let _ = std::fs::remove_dir_all(&path);
+        let space = Config::new(path).open_transactional().unwrap();
+        let hnsw = HNSW::new(FjallStore::open(&space, "dot"), 20, 200, 16, Dist::Dot);
+        let ids = hnsw.insert_batch((1..=50).map(|i| vec![i as f32, 1.]).collect()).unwrap();
+        let result = hnsw.search(vec![1., 0.], 2).unwrap();
+        assert_eq!(result, vec![(ids[49], -50.), (ids[48], -49.)]);
+        assert!(hnsw.insert(vec![f32::NAN, 1.]).is_err());
+
+        let hnsw = HNSW::new(FjallStore::open(&space, "cosine"), 20, 200, 16, Dist::CosineNormalized);
+        let ids = hnsw.insert_batch((0..50).map(|i| vec![(i + 1) as f32, i as f32]).collect()).unwrap();
+        assert_eq!(hnsw.get(ids[0]).unwrap(), Some(vec![1., 0.]));
+        hnsw.set_arrow(ids[1], vec![0., 3.]).unwrap();
+        assert_eq!(hnsw.get(ids[1]).unwrap(), Some(vec![0., 1.]));
+        let result = hnsw.search(vec![0., 10.], 1).unwrap();
+        assert_eq!(result, vec![(ids[1], 0.)]);
+        assert!(hnsw.insert(vec![0., 0.]).is_err());
+        assert!(hnsw.search(vec![0., 0.], 1).is_err());
+    }
+
+    #[test]
+    fn test_quantization() {
+        let hnsw = open("arrowdb_test_quantize").with_quantization();
+        let mut seed = 7u64;
+        let mut rand = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 40) as f32 / (1u64 << 24) as f32
+        };
+        let vectors: Vec<Vec<f32>> = (0..1200).map(|_| (0..8).map(|_| rand()).collect()).collect();
+        assert!(hnsw.train_quantizer().is_ok());
+        let ids = hnsw.insert_batch(vectors.clone()).unwrap();
+        assert!(hnsw.is_quantized());                              //超过 1000 个向量自动训练
+        assert!(hnsw.arrows.is_empty());
+        assert_eq!(hnsw.get(ids[3]).unwrap(), Some(vectors[3].clone()));
+        let id = hnsw.insert(vec![0.5; 8]).unwrap();
+        assert!(hnsw.arrows.is_empty());
+        assert_eq!(hnsw.get(id).unwrap(), Some(vec![0.5; 8]));
+
+        let query = vec![0.3; 8];
+        let result = hnsw.search(query.clone(), 10).unwrap();
+        let exact = hnsw.search_exact(query.clone(), 10).unwrap();
+        for (id, dist) in &result {
+            let arrow = hnsw.get(*id).unwrap().unwrap();
+            assert_eq!(*dist, Dist::L2.eval(&query, &arrow));       //最终距离是用原始向量计算的
+        }
+        let trained = hnsw.quantizer().unwrap();
+        assert!(!trained.codes.is_empty() && hnsw.arrows.is_empty());   //搜索只缓存编码
+        hnsw.train_quantizer().unwrap();                        //重新训练 旧的编码留在旧的量化器里
+        assert!(hnsw.quantizer().unwrap().codes.is_empty() && !trained.codes.is_empty());
+        let hit = result.iter().filter(|r| exact.contains(r)).count();
+        assert!(hit >= 8, "recall {}", hit);
+        let radius = exact[4].1;
+        assert!(hnsw.search_radius(query, radius, 100).unwrap().iter().all(|(_, dist)| *dist <= radius));
+
+        let reopened = HNSW::<FjallStore>::new(hnsw.store.clone(), 20, 200, 16, Dist::L2).with_quantization();
+        assert!(reopened.is_quantized());
+        assert!(HNSW::<FjallStore>::new(hnsw.store.clone(), 20, 200, 16, Dist::L2).train_quantizer().is_err());
     }
 }
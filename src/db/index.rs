@@ -0,0 +1,159 @@
+//payload 字段上的二级索引 每个索引是一个单独的分区
+//key 是 编码后的字段值 + id(大端) value 为空 关键字 和 布尔 通过前缀得到 id 集合 数值类型 通过范围扫描
+//数值索引必须包含所有的数值 否则用索引和不用索引的结果会不一样
+use super::filter::Filter;
+use super::payload::{Payload, Value};
+use crate::store::KVStore;
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum IndexKind {
+    Keyword,
+    Integer,                            //整数部分 + 小数部分 整数值没有精度损失 带小数的浮点值也会进入索引
+    Float,
+    Bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct PayloadIndex<T: KVStore> {
+    pub(crate) kind: IndexKind,
+    store: T,
+}
+
+fn sortable_f64(v: f64) -> [u8; 8] {
+    let v = if v == 0. { 0. } else { v };           //-0.0 和 0.0 相等
+    let bits = v.to_bits();
+    let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+    bits.to_be_bytes()
+}
+
+fn sortable_i64(v: i64) -> [u8; 8] {
+    ((v as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn sortable_int(v: i64, fract: f64) -> Vec<u8> {
+    let mut b = sortable_i64(v).to_vec();
+    b.extend_from_slice(&sortable_f64(fract));
+    b
+}
+
+fn id_key(value: &[u8], id: u64) -> Bytes {
+    let mut b = BytesMut::with_capacity(value.len() + 8);
+    b.extend_from_slice(value);
+    b.extend_from_slice(&id.to_be_bytes());
+    b.freeze()
+}
+
+impl<T: KVStore> PayloadIndex<T> {
+    pub(crate) fn new(kind: IndexKind, store: T) -> Self {
+        Self { kind, store }
+    }
+
+    //字段值 编码成可以排序的字节 类型不匹配的值不进入索引
+    fn encode(&self, value: &Value) -> Option<Vec<u8>> {
+        match (self.kind, value) {
+            (IndexKind::Keyword, Value::String(s)) => {
+                let mut b = (s.len() as u32).to_be_bytes().to_vec();
+                b.extend_from_slice(s.as_bytes());
+                Some(b)
+            }
+            (IndexKind::Integer, Value::Int(v)) => Some(sortable_int(*v, 0.)),
+            (IndexKind::Integer | IndexKind::Float, Value::Float(v)) if !v.is_nan() => Some(self.number(*v)),
+            (IndexKind::Float, Value::Int(v)) => Some(self.number(*v as f64)),
+            (IndexKind::Bool, Value::Bool(v)) => Some(vec![*v as u8]),
+            _ => None,
+        }
+    }
+
+    fn number(&self, v: f64) -> Vec<u8> {
+        match self.kind {
+            IndexKind::Integer if v.is_finite() => sortable_int(v.floor() as i64, v - v.floor()),
+            IndexKind::Integer => sortable_int(v as i64, 0.),
+            _ => sortable_f64(v).to_vec(),
+        }
+    }
+
+    fn keys(&self, id: u64, value: &Value) -> Vec<Bytes> {
+        match value {
+            Value::Array(values) => values.iter().filter_map(|v| self.encode(v)).map(|v| id_key(&v, id)).collect(),
+            value => self.encode(value).map(|v| vec![id_key(&v, id)]).unwrap_or_default(),
+        }
+    }
+
+    pub(crate) fn insert(&self, id: u64, field: &str, payload: &Payload) -> Result<()> {
+        if let Some(value) = payload.get(field) {
+            for key in self.keys(id, value) {
+                self.store.set(key, Bytes::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, id: u64, field: &str, payload: &Payload) -> Result<()> {
+        if let Some(value) = payload.get(field) {
+            for key in self.keys(id, value) {
+                self.store.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn id_of(key: &Bytes) -> u64 {
+        u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap())
+    }
+
+    fn eq_ids(&self, value: &Value, ids: &mut FxHashSet<u64>) -> Result<bool> {
+        if let Some(prefix) = self.encode(value) {
+            for kv in self.store.scan(Bytes::from_owner(prefix)) {
+                ids.insert(Self::id_of(&kv?.0));
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn range_ids(&self, start: Bound<f64>, end: Bound<f64>) -> Result<FxHashSet<u64>> {
+        let start = match start {
+            Bound::Included(v) => Bound::Included(id_key(&self.number(v), 0)),
+            Bound::Excluded(v) => Bound::Excluded(id_key(&self.number(v), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match end {
+            Bound::Included(v) => Bound::Included(id_key(&self.number(v), u64::MAX)),
+            Bound::Excluded(v) => Bound::Excluded(id_key(&self.number(v), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut ids = FxHashSet::default();
+        for kv in self.store.range(start, end) {
+            ids.insert(Self::id_of(&kv?.0));
+        }
+        Ok(ids)
+    }
+
+    //能够通过索引得到的 id 集合 返回 None 表示这个条件不能用索引
+    pub(crate) fn ids(&self, filter: &Filter) -> Result<Option<FxHashSet<u64>>> {
+        let mut ids = FxHashSet::default();
+        match filter {
+            Filter::Eq(_, value) => {
+                if !self.eq_ids(value, &mut ids)? {
+                    return Ok(None);
+                }
+            }
+            Filter::In(_, values) => {
+                for value in values {
+                    if !self.eq_ids(value, &mut ids)? {
+                        return Ok(None);
+                    }
+                }
+            }
+            Filter::Range(_, start, end) if matches!(self.kind, IndexKind::Integer | IndexKind::Float) => return self.range_ids(*start, *end).map(Some),
+            _ => return Ok(None),
+        }
+        Ok(Some(ids))
+    }
+}
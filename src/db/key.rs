@@ -0,0 +1,46 @@
+//用户自己的主键 映射到内部的 u64 id
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Int(u64),
+    Str(String),
+}
+
+impl From<u64> for Key {
+    fn from(v: u64) -> Self { Self::Int(v) }
+}
+
+impl From<&str> for Key {
+    fn from(v: &str) -> Self { Self::Str(v.into()) }
+}
+
+impl From<String> for Key {
+    fn from(v: String) -> Self { Self::Str(v) }
+}
+
+impl Key {
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        let mut b = BytesMut::with_capacity(16);
+        match self {
+            Self::Int(v) => {
+                b.put_u8(0);
+                b.extend_from_slice(&v.to_be_bytes());
+            }
+            Self::Str(s) => {
+                b.put_u8(1);
+                b.extend_from_slice(s.as_bytes());
+            }
+        }
+        b.freeze()
+    }
+
+    pub(crate) fn from_bytes(buf: &[u8]) -> Result<Self> {
+        match buf.split_first() {
+            Some((0, v)) => Ok(Self::Int(u64::from_be_bytes(v.try_into()?))),
+            Some((1, v)) => Ok(Self::Str(String::from_utf8(v.to_vec())?)),
+            _ => Err(anyhow!("invalid key {:?}", buf)),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//用户自定义的距离函数 按照名字注册 集合的 catalog 里只保存名字
+//打开集合时按照名字查找 没有注册的话 打开失败
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+pub trait Metric: Send + Sync {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32;
+}
+
+impl<F: Fn(&[f32], &[f32]) -> f32 + Send + Sync> Metric for F {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        self(va, vb)
+    }
+}
+
+fn metrics() -> &'static RwLock<HashMap<String, Arc<dyn Metric>>> {
+    static METRICS: OnceLock<RwLock<HashMap<String, Arc<dyn Metric>>>> = OnceLock::new();
+    METRICS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+//同一个名字重复注册时 替换原来的 已经打开的集合仍然使用原来的
+pub fn register_metric<M: Metric + 'static>(name: &str, metric: M) {
+    metrics().write().unwrap().insert(name.into(), Arc::new(metric));
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CustomDist {
+    pub(crate) name: String,
+    #[serde(skip)]
+    pub(crate) metric: Option<Arc<dyn Metric>>,             //从 catalog 读出来时为空 打开集合时通过 resolve 设置
+}
+
+impl std::fmt::Debug for CustomDist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Custom({})", self.name)
+    }
+}
+
+impl CustomDist {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn resolve(name: &str) -> Result<Self> {
+        let metric = metrics().read().unwrap().get(name).cloned().ok_or(anyhow!("distance metric {:?} is not registered", name))?;
+        Ok(Self { name: name.into(), metric: Some(metric) })
+    }
+
+    pub(crate) fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        match &self.metric {
+            Some(metric) => metric.eval(va, vb),
+            None => panic!("distance metric {:?} is not resolved", self.name),
+        }
+    }
+}
@@ -13,9 +13,14 @@
 
 use anndists::dist::*;
 use hnsw::HNSW;
+use element::{Bits, Element, ElementType, BF16, F16};
+use index::IndexKind;
+use metric::CustomDist;
+use search::{Hit, SearchOptions, SearchParams, Strategy};
 use fjall::{Config, TxKeyspace};
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
 use anyhow::{anyhow, Result};
 
@@ -26,6 +31,15 @@ pub enum Dist {
     L1,
     L2,
     Cosine,
+    Dot,                    //内积的相反数 可以是负数
+    CosineNormalized,       //写入和查询时先归一化 然后用 1 - 内积 作为距离
+    Custom(CustomDist),     //通过 register_metric 注册的距离函数
+    Hamming,                //不相同的分量个数 二进制向量是不相同的位数
+    Jaccard,                //1 - sum(min) / sum(max) 二进制向量是 1 - |a & b| / |a | b|
+}
+
+fn dot(va: &[f32], vb: &[f32]) -> f32 {
+    va.iter().zip(vb).map(|(a, b)| a * b).sum()
 }
 
 impl Dist {
@@ -33,9 +47,46 @@ impl Dist {
         match self {
             Self::L1=> DistL1{}.eval(va, vb),
             Self::L2=> DistL2{}.eval(va, vb),
-            Self::Cosine=> DistCosine{}.eval(va, vb)
+            Self::Cosine=> DistCosine{}.eval(va, vb),
+            Self::Dot=> -dot(va, vb),
+            Self::CosineNormalized=> (1. - dot(va, vb)).max(0.),
+            Self::Custom(custom)=> custom.eval(va, vb),
+            Self::Hamming | Self::Jaccard=> element::float_distance(self, va.iter().copied(), vb.iter().copied()),
+        }
+    }
+
+    //使用已经注册的距离函数
+    pub fn custom(name: &str) -> Result<Self> {
+        Ok(Self::Custom(CustomDist::resolve(name)?))
+    }
+
+    //从 catalog 读出来的自定义距离 需要按照名字重新找到注册的函数
+    fn resolve(&self) -> Result<Self> {
+        match self {
+            Self::Custom(custom) => Self::custom(custom.name()),
+            dist => Ok(dist.clone()),
         }
     }
+
+    //写入和查询之前检查向量 NaN 会在 OrderId 的比较里 panic 余弦距离不接受零向量
+    pub fn prepare<E: Element>(&self, mut arrow: Vec<E>) -> Result<Vec<E>> {
+        if arrow.iter().any(|v| !v.to_f32().is_finite()) {
+            return Err(anyhow!("vector contains NaN or infinite value"));
+        }
+        if matches!(self, Self::Cosine | Self::CosineNormalized) {
+            let norm = arrow.iter().map(|v| v.to_f32() * v.to_f32()).sum::<f32>().sqrt();
+            if norm == 0. || !norm.is_finite() {
+                return Err(anyhow!("zero vector can not be used with cosine distance"));
+            }
+            if matches!(self, Self::CosineNormalized) {
+                if !E::FLOAT {
+                    return Err(anyhow!("CosineNormalized needs a float element type, use Cosine for {:?}", E::TYPE));
+                }
+                arrow.iter_mut().for_each(|v| *v = E::from_f32(v.to_f32() / norm));
+            }
+        }
+        Ok(arrow)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -45,11 +96,109 @@ pub struct Collection {
     nb_conn: usize,                     //邻居
     ef: usize,                          //构建邻居
     dist: Dist,                         //距离类型
+    #[serde(default)]
+    indexes: BTreeMap<String, IndexKind>,  //payload 字段索引 每个索引是单独的分区
+    #[serde(default)]
+    flat_threshold: usize,              //向量数量不超过这个值时 使用暴力搜索
+    #[serde(default)]
+    element: ElementType,               //向量分量的类型
+    #[serde(default)]
+    quantize: bool,                     //内存里只保存 int8 标量量化编码 磁盘上保留原始向量
 }
 
 impl Collection {
     fn dim(dimension: usize) -> Self {
-        Self{dimension, max_layer: 16, nb_conn: 20, ef: 200, dist: Dist::L2}
+        Self{dimension, max_layer: 16, nb_conn: 20, ef: 200, dist: Dist::L2, indexes: BTreeMap::new(), flat_threshold: 0, element: ElementType::F32, quantize: false}
+    }
+
+    pub fn dimension(&self)-> usize {
+        self.dimension
+    }
+
+    pub fn element(&self)-> ElementType {
+        self.element
+    }
+
+    fn check(&self)-> Result<()> {
+        if self.dimension == 0 { return Err(anyhow!("collection dimension must be greater than 0")); }
+        if self.element == ElementType::Binary && !self.dimension.is_multiple_of(8) {
+            return Err(anyhow!("binary collection dimension {} must be a multiple of 8", self.dimension));
+        }
+        if self.nb_conn < 2 { return Err(anyhow!("nb_conn {} must be at least 2", self.nb_conn)); }
+        if self.max_layer == 0 || self.max_layer > 1 << (64 - ID_BITS) {
+            return Err(anyhow!("max_layer {} must be in 1..={}", self.max_layer, 1 << (64 - ID_BITS)));
+        }
+        for field in self.indexes.keys() {
+            check_field(field)?;
+        }
+        self.dist.resolve()?;
+        if matches!(self.dist, Dist::CosineNormalized) && matches!(self.element, ElementType::I8 | ElementType::U8 | ElementType::Binary) {
+            return Err(anyhow!("CosineNormalized needs a float element type, use Cosine for {:?}", self.element));
+        }
+        if self.quantize && !matches!(self.element, ElementType::F32 | ElementType::F16 | ElementType::BF16) {
+            return Err(anyhow!("scalar quantization needs a float element type, not {:?}", self.element));
+        }
+        Ok(())
+    }
+}
+
+//集合名和索引字段名都会成为分区名的一部分 不能包含索引分区的分隔符 $ 和目录分区的前缀 #
+fn valid_name(name: &str)-> bool {
+    !name.is_empty() && name.len() <= 128 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn check_field(field: &str)-> Result<()> {
+    if !valid_name(field) {
+        return Err(anyhow!("index field {:?} may only contain ascii alphanumerics, '_' and '-'", field));
+    }
+    Ok(())
+}
+
+fn index_partition(name: &str, field: &str)-> String {
+    format!("{}${}", name, field)
+}
+
+//创建集合的参数 没有设置的使用默认值 max_layer 16 nb_conn 20 ef 200 L2
+#[derive(Clone, Debug)]
+pub struct CollectionOptions {
+    collection: Collection,
+}
+
+impl CollectionOptions {
+    pub fn new(dimension: usize)-> Self {
+        Self{collection: Collection::dim(dimension)}
+    }
+    pub fn dist(mut self, dist: Dist)-> Self {
+        self.collection.dist = dist;
+        self
+    }
+    pub fn max_layer(mut self, max_layer: usize)-> Self {
+        self.collection.max_layer = max_layer;
+        self
+    }
+    pub fn nb_conn(mut self, nb_conn: usize)-> Self {
+        self.collection.nb_conn = nb_conn;
+        self
+    }
+    pub fn ef(mut self, ef: usize)-> Self {
+        self.collection.ef = ef;
+        self
+    }
+    pub fn flat_threshold(mut self, flat_threshold: usize)-> Self {
+        self.collection.flat_threshold = flat_threshold;
+        self
+    }
+    pub fn element(mut self, element: ElementType)-> Self {
+        self.collection.element = element;
+        self
+    }
+    pub fn quantize(mut self, quantize: bool)-> Self {
+        self.collection.quantize = quantize;
+        self
+    }
+    pub fn index(mut self, field: &str, kind: IndexKind)-> Self {
+        self.collection.indexes.insert(field.into(), kind);
+        self
     }
 }
 
@@ -60,17 +209,60 @@ pub struct ArrowDB  {
     //collect_partion: TxPartition,
     store: FjallStore,
     collections: Arc<RwLock<HashMap<String, Collection>>>,
-    hnsws: Arc<RwLock<HashMap<String, hnsw::HNSW<FjallStore>>>>,
+    hnsws: Arc<RwLock<HashMap<String, Arc<dyn Opened>>>>,
+}
+
+//已经打开的集合 元素类型不同的 HNSW 通过 as_any 转换回具体的类型
+trait Opened: Send + Sync {
+    fn close(&self);
+    fn add_index(&self, field: &str, kind: IndexKind, store: FjallStore, rebuild: bool)-> Result<()>;
+    fn as_any(&self)-> &dyn Any;
+    fn recommend(&self, positive: &[u64], negative: &[u64], k: usize, strategy: Strategy)-> Result<Vec<(u64, f32)>>;
+}
+
+impl<E: Element> Opened for HNSW<FjallStore, E> {
+    fn close(&self) { HNSW::close(self) }
+    fn add_index(&self, field: &str, kind: IndexKind, store: FjallStore, rebuild: bool)-> Result<()> { HNSW::add_index(self, field, kind, store, rebuild) }
+    fn as_any(&self)-> &dyn Any { self }
+    fn recommend(&self, positive: &[u64], negative: &[u64], k: usize, strategy: Strategy)-> Result<Vec<(u64, f32)>> { HNSW::recommend(self, positive, negative, k, strategy) }
+}
+
+//集合的维度 二进制向量是位数 每个 Bits 8 位
+fn dim_of<E: Element>(len: usize)-> usize {
+    if E::TYPE == ElementType::Binary { len * 8 } else { len }
 }
 
 //需要有一些参数的定义 每一个集合 比如说 max 层数 维度 距离函数 临近数量等
 impl ArrowDB {
-    fn add_hnsw(&self, name: &str, collection: &Collection) {
+    fn add_hnsw(&self, name: &str, collection: &Collection)-> Result<Arc<dyn Opened>> {
+        if let Some(hnsw) = self.hnsws.read().unwrap().get(name) {
+            return Ok(hnsw.clone());
+        }
+        let hnsw: Arc<dyn Opened> = match collection.element {
+            ElementType::F32 => Arc::new(self.open_hnsw::<f32>(name, collection)?),
+            ElementType::F16 => Arc::new(self.open_hnsw::<F16>(name, collection)?),
+            ElementType::BF16 => Arc::new(self.open_hnsw::<BF16>(name, collection)?),
+            ElementType::I8 => Arc::new(self.open_hnsw::<i8>(name, collection)?),
+            ElementType::U8 => Arc::new(self.open_hnsw::<u8>(name, collection)?),
+            ElementType::Binary => Arc::new(self.open_hnsw::<Bits>(name, collection)?),
+        };
+        self.hnsws.write().unwrap().insert(name.into(), hnsw.clone());
+        Ok(hnsw)
+    }
+
+    fn open_hnsw<E: Element>(&self, name: &str, collection: &Collection)-> Result<HNSW<FjallStore, E>> {
+        let dist = collection.dist.resolve()?;
         let store = FjallStore::open(&self.space, name);
-        self.hnsws.write().unwrap().insert(name.into(),
-            hnsw::HNSW::new(store, collection.nb_conn, collection.ef, collection.max_layer, Dist::L2)
-        );
+        let mut hnsw = hnsw::HNSW::new(store, collection.nb_conn, collection.ef, collection.max_layer, dist).with_flat_threshold(collection.flat_threshold);
+        if collection.quantize {
+            hnsw = hnsw.with_quantization();
+        }
+        for (field, kind) in &collection.indexes {
+            hnsw.add_index(field, *kind, FjallStore::open(&self.space, &index_partition(name, field)), false)?;
+        }
+        Ok(hnsw)
     }
+
     pub fn new(path: &str)-> Self{
         let space = Config::new(path).open_transactional().unwrap();
         let mut collections = HashMap::new();
@@ -86,46 +278,124 @@ impl ArrowDB {
     }
 
     pub fn get_collections(&self)-> Vec<String> {
-        self.collections.read().unwrap().keys().map(|k| k.clone() ).collect()
+        self.collections.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn get_collection(&self, name: &str)-> Option<Collection> {
+        self.collections.read().unwrap().get(name).cloned()
     }
 
     pub fn create_collection(&self, name: &str, dimension: usize)-> Result<()> {
-        let c = Collection::dim(dimension);
+        self.create_collection_with(name, CollectionOptions::new(dimension))
+    }
+
+    pub fn create_collection_with(&self, name: &str, options: CollectionOptions)-> Result<()> {
+        if !valid_name(name) {
+            return Err(anyhow!("collection name {:?} may only contain ascii alphanumerics, '_' and '-'", name));
+        }
+        let c = options.collection;
+        c.check()?;
+        let mut collections = self.collections.write().unwrap();
+        if collections.contains_key(name) {                 //修改参数需要先 drop_collection 否则旧的分区数据会按新的参数解释
+            return Err(anyhow!("collection {} already existed", name));
+        }
         self.store.set(Bytes::copy_from_slice(name.as_bytes()), Bytes::from_owner(rmp_serde::to_vec(&c).unwrap()))?;
-        self.add_hnsw(name, &c);
-        self.collections.write().unwrap().insert(name.into(), c);
+        self.add_hnsw(name, &c)?;
+        collections.insert(name.into(), c);
         Ok(())
     }
 
+    pub fn drop_collection(&self, name: &str)-> Result<()> {
+        let mut collections = self.collections.write().unwrap();       //整个删除过程持有锁 同名的集合不能在中间被创建
+        let c = collections.remove(name).ok_or(anyhow!("collection {} do not existed", name))?;
+        self.store.remove(Bytes::copy_from_slice(name.as_bytes()))?;
+        if let Some(hnsw) = self.hnsws.write().unwrap().remove(name) {
+            hnsw.close();                                   //还在使用的 clone 会返回错误 而不是写入已经删除的分区
+        }
+        for field in c.indexes.keys() {
+            let store = FjallStore::open(&self.space, &index_partition(name, field));
+            self.space.delete_partition(store.tx)?;
+        }
+        let store = FjallStore::open(&self.space, name);
+        self.space.delete_partition(store.tx)?;
+        Ok(())
+    }
+
+    //在已有的集合上建立 payload 字段索引 已经存在的 payload 会加入索引
+    pub fn create_index(&self, name: &str, field: &str, kind: IndexKind)-> Result<()> {
+        check_field(field)?;
+        let mut collections = self.collections.write().unwrap();
+        let c = collections.get_mut(name).ok_or(anyhow!("collection {} do not existed", name))?;
+        if c.indexes.contains_key(field) {
+            return Err(anyhow!("index on {} already existed", field));
+        }
+        let hnsw = self.add_hnsw(name, c)?;
+        hnsw.add_index(field, kind, FjallStore::open(&self.space, &index_partition(name, field)), true)?;
+        c.indexes.insert(field.into(), kind);
+        self.store.set(Bytes::copy_from_slice(name.as_bytes()), Bytes::from_owner(rmp_serde::to_vec(c)?))
+    }
+
+    //向量的元素类型需要和集合的 element 一致 和 get_hnsw_as 一样
+    pub fn search_with<E: Element>(&self, name: &str, data: Vec<E>, params: &SearchParams)-> Result<Vec<(u64, f32)>> {
+        self.get_hnsw_as::<E>(name, dim_of::<E>(data.len()))?.search_with(data, params)
+    }
+
+    pub fn search_hits<E: Element>(&self, name: &str, data: Vec<E>, params: &SearchParams, options: &SearchOptions)-> Result<Vec<Hit<E>>> {
+        self.get_hnsw_as::<E>(name, dim_of::<E>(data.len()))?.search_hits(data, params, options)
+    }
+
+    pub fn search_mmr<E: Element>(&self, name: &str, data: Vec<E>, params: &SearchParams, lambda: f32)-> Result<Vec<(u64, f32)>> {
+        self.get_hnsw_as::<E>(name, dim_of::<E>(data.len()))?.search_mmr(data, params, lambda)
+    }
+
+    pub fn recommend(&self, name: &str, positive: &[u64], negative: &[u64], k: usize, strategy: Strategy)-> Result<Vec<(u64, f32)>> {
+        let c = self.collections.read().unwrap().get(name).cloned().ok_or(anyhow!("collection {} do not existed", name))?;
+        self.add_hnsw(name, &c)?.recommend(positive, negative, k, strategy)       //只有 id 没有向量 按照集合的元素类型分发
+    }
+
+    pub fn search_batch<E: Element>(&self, name: &str, queries: Vec<Vec<E>>, number: usize)-> Result<Vec<Vec<(u64, f32)>>> {
+        self.search_batch_with(name, queries, &SearchParams::new(number), 0)
+    }
+
+    //threads 为 0 时使用 rayon 的全局线程池
+    pub fn search_batch_with<E: Element>(&self, name: &str, queries: Vec<Vec<E>>, params: &SearchParams, threads: usize)-> Result<Vec<Vec<(u64, f32)>>> {
+        let dim = match queries.first() {
+            Some(first) => first.len(),
+            None => return Ok(Vec::new()),
+        };
+        if let Some(query) = queries.iter().find(|q| q.len() != dim) {
+            return Err(anyhow!("query dimension {} is not equal {}", query.len(), dim));
+        }
+        self.get_hnsw_as::<E>(name, dim_of::<E>(dim))?.search_batch_with(queries, params, threads)
+    }
+
     pub fn get_hnsw(&self, name: &str, dim: usize)-> Result<HNSW<FjallStore>> {
+        self.get_hnsw_as::<f32>(name, dim)
+    }
+
+    //元素类型需要和创建集合时的 element 一致 二进制集合的 dim 是位数 每个 Bits 是 8 位
+    pub fn get_hnsw_as<E: Element>(&self, name: &str, dim: usize)-> Result<HNSW<FjallStore, E>> {
         if let Some(info) = self.collections.read().unwrap().get(name) {
             if info.dimension != dim {
                 return Err(anyhow!("collection dimension {} is not equal {}", info.dimension, dim));
             }
-            if !self.hnsws.read().unwrap().contains_key(name) {
-                self.add_hnsw(name, info);
-            }
-            Ok(self.hnsws.read().unwrap().get(name).unwrap().clone())
+            let hnsw = self.add_hnsw(name, info)?;
+            hnsw.as_any().downcast_ref::<HNSW<FjallStore, E>>().cloned().ok_or(anyhow!("collection {} stores {:?} not {:?}", name, info.element, E::TYPE))
         } else {
             Err(anyhow!("collection {} do not existed", name))
         }
     }
 }
 
-use std::mem;
-pub(crate) fn u8_to_vec<T: Clone>(u8_vec: Vec<u8>) -> Vec<T> {
-    let len = u8_vec.len() / mem::size_of::<T>();
-    let ptr = u8_vec.as_ptr() as *const T;
-    mem::forget(u8_vec);
-    unsafe { Vec::from_raw_parts(ptr as *mut T, len, len) }//.clone()
+//按分量逐个转换 不依赖内存对齐和本机字节序
+pub(crate) fn u8_to_vec<E: Element>(bytes: &[u8]) -> Vec<E> {
+    bytes.chunks_exact(std::mem::size_of::<E>()).map(E::read_le).collect()
 }
 
-
-pub(crate) fn vec_to_u8<T>(vec: Vec<T>) -> Vec<u8> {
-    let len = vec.len() * mem::size_of::<T>();
-    let ptr = vec.as_ptr() as *const u8;
-    mem::forget(vec);
-    unsafe { Vec::from_raw_parts(ptr as *mut u8, len, len) }
+pub(crate) fn vec_to_u8<E: Element>(vec: &[E]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(std::mem::size_of_val(vec));
+    vec.iter().for_each(|v| v.write_le(&mut buf));
+    buf
 }
 
 pub(crate) trait PersistID {
@@ -133,12 +403,246 @@ pub(crate) trait PersistID {
     fn get_id(&self) -> u64;
     fn entry(&self)-> (usize, u64);
     fn set_entry(&self, level: usize, id: u64);
+    fn replace_entry(&self, level: usize, id: u64);
 }
 
 pub(crate) const ID_BITS: usize = 64 - 4;              //2 的 4 次方层 最大 0-15 已经足够了
 pub(crate) const ID_MASK: u64 = 0xfffffffffffffffu64;
 
+pub mod dataset;
+pub mod element;
+pub mod eval;
+pub mod filter;
 pub mod hnsw;
+pub mod index;
+pub mod key;
 mod layer;
+pub mod metric;
 pub mod order_id;
+pub mod payload;
+pub mod quantize;
+pub mod search;
 mod unique_id;
+
+#[cfg(test)]
+mod tests {
+    use super::{ArrowDB, Collection, CollectionOptions, CustomDist, Dist, KVStore};
+    use super::element::{Bits, ElementType, F16};
+    use super::search::{SearchOptions, SearchParams, Strategy};
+    use super::filter::Filter;
+    use super::index::IndexKind;
+    use super::payload::payload;
+
+    fn open(name: &str)-> ArrowDB {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        ArrowDB::new(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_drop_collection() {
+        let db = open("arrowdb_test_drop");
+        db.create_collection("drop", 4).unwrap();
+        let hnsw = db.get_hnsw("drop", 4).unwrap();
+        hnsw.insert(vec![0., 1., 2., 3.]).unwrap();
+        db.drop_collection("drop").unwrap();
+        assert!(db.get_collections().is_empty());
+        assert!(db.get_hnsw("drop", 4).is_err());
+        assert!(hnsw.insert(vec![0., 1., 2., 3.]).is_err());
+        assert!(hnsw.search(vec![0., 1., 2., 3.], 1).is_err());
+        assert!(db.drop_collection("drop").is_err());
+
+        drop(hnsw);
+        db.create_collection("drop", 4).unwrap();
+        let hnsw = db.get_hnsw("drop", 4).unwrap();
+        assert!(hnsw.search(vec![0., 1., 2., 3.], 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drop_while_running() {
+        let db = open("arrowdb_test_drop_running");
+        db.create_collection("drop", 2).unwrap();
+        let hnsw = db.get_hnsw("drop", 2).unwrap();
+        hnsw.insert_batch((0..500).map(|i| vec![(i % 25) as f32, (i / 25) as f32]).collect()).unwrap();
+        let running: Vec<_> = (0..4).map(|t| {
+            let hnsw = hnsw.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    let _ = hnsw.search(vec![((i + t) % 25) as f32, (i % 20) as f32], 5);
+                    let _ = hnsw.insert_batch((0..10).map(|j| vec![(i + j) as f32 / 10., t as f32]).collect());
+                }
+            })
+        }).collect();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        db.drop_collection("drop").unwrap();
+        for r in running {
+            r.join().unwrap();                              //正在进行的操作返回错误 不会 panic
+        }
+        assert!(hnsw.search(vec![0., 0.], 1).is_err());
+    }
+
+    #[test]
+    fn test_collection_options() {
+        let path = std::env::temp_dir().join("arrowdb_test_options");
+        let _ = std::fs::remove_dir_all(&path);
+        {
+            let db = ArrowDB::new(path.to_str().unwrap());
+            assert!(db.create_collection_with("bad", CollectionOptions::new(4).nb_conn(1)).is_err());
+            assert!(db.create_collection_with("bad", CollectionOptions::new(4).max_layer(17)).is_err());
+            db.create_collection_with("cos", CollectionOptions::new(2).dist(Dist::Cosine).nb_conn(8).ef(64).max_layer(8)).unwrap();
+            let hnsw = db.get_hnsw("cos", 2).unwrap();
+            hnsw.insert(vec![1., 0.]).unwrap();
+            hnsw.insert(vec![10., 1.]).unwrap();
+            hnsw.insert(vec![0., 2.]).unwrap();
+            assert!(db.create_collection_with("cos", CollectionOptions::new(16).element(ElementType::U8)).is_err());
+            assert!(db.create_collection("cos$tenant", 2).is_err());         //会和 cos 的索引分区重名
+            assert!(db.create_collection("#collections", 2).is_err());
+        }
+        let db = ArrowDB::new(path.to_str().unwrap());
+        assert_eq!(db.get_collections(), vec!["cos".to_string()]);
+        assert_eq!(db.get_collection("cos").unwrap().dimension(), 2);
+        let hnsw = db.get_hnsw("cos", 2).unwrap();
+        assert!(matches!(hnsw.dist_f, Dist::Cosine));
+        let result = hnsw.search(vec![1., 0.1], 1).unwrap();     //L2 最近的是 1,0 cosine 最近的是同方向的 10,1
+        assert_eq!(result[0].0, 1);
+        let result = db.search_batch_with("cos", vec![vec![1f32, 0.1], vec![0., 1.]], &SearchParams::new(2).ef(16), 2).unwrap();
+        assert_eq!(result.iter().map(|r| r[0].0).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(db.search_batch("cos", vec![vec![1f32, 0.1]], 3).unwrap()[0].len(), 3);
+        assert!(db.search_batch_with("cos", vec![vec![1f32, 0.1], vec![1.]], &SearchParams::new(1), 0).is_err());
+    }
+
+    #[test]
+    fn test_custom_metric() {
+        let path = std::env::temp_dir().join("arrowdb_test_metric");
+        let _ = std::fs::remove_dir_all(&path);
+        super::metric::register_metric("weighted_l2", |a: &[f32], b: &[f32]| a.iter().zip(b).zip([1., 100.]).map(|((a, b), w)| w * (a - b) * (a - b)).sum::<f32>());
+        assert!(Dist::custom("missing").is_err());
+        {
+            let db = ArrowDB::new(path.to_str().unwrap());
+            let missing = Dist::Custom(CustomDist { name: "missing".into(), metric: None });
+            assert!(db.create_collection_with("bad", CollectionOptions::new(2).dist(missing.clone())).is_err());
+            db.create_collection_with("weighted", CollectionOptions::new(2).dist(Dist::custom("weighted_l2").unwrap())).unwrap();
+            let mut c = Collection::dim(2);
+            c.dist = missing;
+            db.store.set("unregistered".into(), rmp_serde::to_vec(&c).unwrap().into()).unwrap();
+        }
+        let db = ArrowDB::new(path.to_str().unwrap());
+        let hnsw = db.get_hnsw("weighted", 2).unwrap();
+        let a = hnsw.insert(vec![2., 0.]).unwrap();
+        hnsw.insert(vec![0., 0.5]).unwrap();
+        assert_eq!(hnsw.search(vec![0., 0.], 1).unwrap(), vec![(a, 4.)]);        //第二维的权重是 100
+        let err = db.get_hnsw("unregistered", 2).err().unwrap();
+        assert!(err.to_string().contains("not registered"));
+    }
+
+    #[test]
+    fn test_element_type() {
+        let path = std::env::temp_dir().join("arrowdb_test_element");
+        let _ = std::fs::remove_dir_all(&path);
+        {
+            let db = ArrowDB::new(path.to_str().unwrap());
+            assert!(db.create_collection_with("bad", CollectionOptions::new(2).element(ElementType::I8).dist(Dist::CosineNormalized)).is_err());
+            assert!(db.create_collection_with("bad", CollectionOptions::new(2).element(ElementType::U8).quantize(true)).is_err());
+            db.create_collection_with("sq", CollectionOptions::new(2).quantize(true)).unwrap();
+            assert!(db.get_hnsw("sq", 2).unwrap().train_quantizer().is_ok());
+            db.create_collection_with("int8", CollectionOptions::new(3).element(ElementType::I8)).unwrap();
+            db.create_collection_with("half", CollectionOptions::new(2).element(ElementType::F16).dist(Dist::Cosine)).unwrap();
+            let hnsw = db.get_hnsw_as::<i8>("int8", 3).unwrap();
+            hnsw.insert_batch((0..100).map(|i| vec![i as i8, -(i as i8), 1]).collect()).unwrap();
+            let hnsw = db.get_hnsw_as::<F16>("half", 2).unwrap();
+            hnsw.insert(vec![F16::from_f32(1.), F16::from_f32(0.)]).unwrap();
+            hnsw.insert(vec![F16::from_f32(0.), F16::from_f32(1.)]).unwrap();
+        }
+        let db = ArrowDB::new(path.to_str().unwrap());
+        assert!(db.get_hnsw("int8", 3).is_err());
+        let hnsw = db.get_hnsw_as::<i8>("int8", 3).unwrap();
+        let result = hnsw.search(vec![41, -41, 1], 2).unwrap();
+        assert_eq!(result[0].1, 0.);
+        assert_eq!(hnsw.get(result[0].0).unwrap(), Some(vec![41, -41, 1]));
+        assert_eq!(result[1].1, 2f32.sqrt());
+        assert_eq!(db.search_mmr("int8", vec![41i8, -41, 1], &SearchParams::new(2), 0.5).unwrap()[0], result[0]);
+        let hnsw = db.get_hnsw_as::<F16>("half", 2).unwrap();
+        let result = hnsw.search(vec![F16::from_f32(0.1), F16::from_f32(2.)], 1).unwrap();
+        assert_eq!(hnsw.get(result[0].0).unwrap(), Some(vec![F16::from_f32(0.), F16::from_f32(1.)]));
+    }
+
+    #[test]
+    fn test_binary() {
+        let db = open("arrowdb_test_binary");
+        assert!(db.create_collection_with("bad", CollectionOptions::new(12).element(ElementType::Binary)).is_err());
+        db.create_collection_with("hash", CollectionOptions::new(64).element(ElementType::Binary).dist(Dist::Hamming)).unwrap();
+        let hnsw = db.get_hnsw_as::<Bits>("hash", 64).unwrap();
+        let hashes: Vec<u64> = (0..200u64).map(|i| i.wrapping_mul(0x9e3779b97f4a7c15)).collect();
+        let ids = hnsw.insert_batch(hashes.iter().map(|h| h.to_le_bytes().map(Bits).to_vec()).collect()).unwrap();
+        let query = hashes[42] ^ 0b101;                                     //翻转两位
+        let result = hnsw.search(query.to_le_bytes().map(Bits).to_vec(), 1).unwrap();
+        assert_eq!(result[0], (ids[42], 2.));
+        assert_eq!(hnsw.get(ids[42]).unwrap(), Some(hashes[42].to_le_bytes().map(Bits).to_vec()));
+        assert!(hnsw.recommend(&[ids[1]], &[], 3, Strategy::AverageVector).is_err());
+        assert_eq!(hnsw.recommend(&[ids[1]], &[], 3, Strategy::BestScore).unwrap().len(), 3);
+
+        //ArrowDB 上的查询按照集合的元素类型 二进制的维度是位数
+        assert_eq!(db.recommend("hash", &[ids[1]], &[], 3, Strategy::BestScore).unwrap().len(), 3);
+        let params = SearchParams::new(1);
+        assert_eq!(db.search_with("hash", query.to_le_bytes().map(Bits).to_vec(), &params).unwrap()[0], (ids[42], 2.));
+        let hits = db.search_hits("hash", query.to_le_bytes().map(Bits).to_vec(), &params, &SearchOptions::new().with_vector()).unwrap();
+        assert_eq!(hits[0].vector, Some(hashes[42].to_le_bytes().map(Bits).to_vec()));
+        assert_eq!(db.search_batch("hash", vec![query.to_le_bytes().map(Bits).to_vec()], 1).unwrap()[0][0].0, ids[42]);
+        assert!(db.search_with("hash", vec![1f32; 64], &params).is_err());
+    }
+
+    #[test]
+    fn test_payload_index() {
+        let path = std::env::temp_dir().join("arrowdb_test_index");
+        let _ = std::fs::remove_dir_all(&path);
+        let filter = Filter::eq("tenant", "a").and(Filter::range("year", 2001.0..=2003.0));
+        {
+            let db = ArrowDB::new(path.to_str().unwrap());
+            assert!(db.create_collection_with("bad", CollectionOptions::new(2).index("a.b", IndexKind::Keyword)).is_err());
+            db.create_collection_with("docs", CollectionOptions::new(2).index("tenant", IndexKind::Keyword)).unwrap();
+            let hnsw = db.get_hnsw("docs", 2).unwrap();
+            for i in 0..20 {
+                let tenant = if i % 2 == 0 { "a" } else { "b" };
+                hnsw.insert_with_payload(vec![i as f32, 0.], &payload([("tenant", tenant.into()), ("year", (2000 + i).into()), ("score", (i as f64 / 10.).into())])).unwrap();
+            }
+            db.create_index("docs", "year", IndexKind::Integer).unwrap();
+            assert!(db.create_index("docs", "year", IndexKind::Integer).is_err());
+            db.create_index("docs", "score", IndexKind::Float).unwrap();
+            assert_eq!(hnsw.count(&filter).unwrap(), 1);
+            hnsw.set_payload(3, &payload([("tenant", "a".into()), ("year", 2003.into())])).unwrap();
+            assert_eq!(hnsw.count(&filter).unwrap(), 2);
+        }
+        let db = ArrowDB::new(path.to_str().unwrap());
+        let hnsw = db.get_hnsw("docs", 2).unwrap();
+        assert_eq!(hnsw.count(&filter).unwrap(), 2);
+        assert_eq!(hnsw.count(&Filter::range("score", 0.5..1.0)).unwrap(), 5);
+        assert_eq!(hnsw.count(&Filter::eq("tenant", "b").not()).unwrap(), 11);
+        let result = hnsw.search_filter(vec![20., 0.], 5, &filter).unwrap();
+        assert_eq!(result.iter().map(|r| r.0).collect::<Vec<_>>(), vec![3, 2]);
+        hnsw.remove(2).unwrap();
+        assert_eq!(hnsw.count(&filter).unwrap(), 1);
+        db.drop_collection("docs").unwrap();
+    }
+
+    #[test]
+    fn test_index_matches_scan() {
+        let db = open("arrowdb_test_index_scan");
+        db.create_collection("plain", 2).unwrap();
+        db.create_collection_with("indexed", CollectionOptions::new(2).index("year", IndexKind::Integer).index("score", IndexKind::Float)).unwrap();
+        let filters = [Filter::range("year", 2001.0..=2003.0), Filter::range("year", 2001.2..2002.), Filter::eq("year", 2001.5), Filter::eq("year", 2002),
+            Filter::range("score", 0.0..), Filter::range("score", ..=0.0), Filter::eq("score", 0), Filter::range("year", 2002.0..).or(Filter::range("score", ..0.5))];
+        for name in ["plain", "indexed"] {
+            let hnsw = db.get_hnsw(name, 2).unwrap();
+            hnsw.insert_with_payload(vec![0., 0.], &payload([("year", 2001.5.into()), ("score", (-0.0).into())])).unwrap();
+            hnsw.insert_with_payload(vec![1., 0.], &payload([("year", 2002.into()), ("score", 1.0.into())])).unwrap();
+            hnsw.insert_with_payload(vec![2., 0.], &payload([("year", (-3.25).into()), ("score", (-2).into())])).unwrap();
+        }
+        let (plain, indexed) = (db.get_hnsw("plain", 2).unwrap(), db.get_hnsw("indexed", 2).unwrap());
+        for filter in &filters {
+            assert_eq!(plain.count(filter).unwrap(), indexed.count(filter).unwrap(), "{:?}", filter);
+            assert_eq!(plain.search_filter(vec![0., 0.], 3, filter).unwrap(), indexed.search_filter(vec![0., 0.], 3, filter).unwrap(), "{:?}", filter);
+        }
+        assert_eq!(indexed.count(&filters[0]).unwrap(), 2);
+        assert_eq!(indexed.count(&filters[4]).unwrap(), 2);
+    }
+}
@@ -22,7 +22,7 @@ impl<T: Clone> Eq for Point<T> {}
 
 impl<T: Clone> PartialOrd for Point<T> {
     fn partial_cmp(&self, other: &Point<T>) -> Option<std::cmp::Ordering> {
-        self.id_level.partial_cmp(&other.id_level)
+        Some(self.cmp(other))
     }
 }
 
@@ -42,6 +42,9 @@ impl<T: Clone> Point<T> {
     pub fn level(&self) -> usize {
         (self.id_level >> ID_BITS) as usize
     }
+    pub(crate) fn at_level(&self, level: usize) -> Self {         //同一个点 作为第 level 层的邻居
+        Self { id_level: level_id(self.id(), level), arrow: self.arrow.clone(), neighbor: self.neighbor.clone() }
+    }
     pub fn to_order_id(&self, dist: f32) -> OrderId<T> {
         OrderId { point: self.clone(), dist }
     }
@@ -67,23 +70,23 @@ impl<T: Clone> OrderId<T> {
 
 impl<T: Clone> PartialEq for OrderId<T> {
     fn eq(&self, other: &OrderId<T>) -> bool {
-        self.dist == other.dist
+        self.dist == other.dist && self.point.id() == other.point.id()
     }
 }
 
 impl<T: Clone> Eq for OrderId<T> {}
 
-#[allow(clippy::non_canonical_partial_ord_impl)]
 impl<T: Clone> PartialOrd for OrderId<T> {
     fn partial_cmp(&self, other: &OrderId<T>) -> Option<std::cmp::Ordering> {
-        self.dist.partial_cmp(&other.dist)
+        Some(self.cmp(other))
     }
 }
 
+//按照 (dist, id) 排序 距离相同时 用 id 保证结果的顺序是确定的
 impl<T: Clone> Ord for OrderId<T> {
     fn cmp(&self, other: &OrderId<T>) -> std::cmp::Ordering {
         if !self.dist.is_nan() && !other.dist.is_nan() {
-            self.dist.partial_cmp(&other.dist).unwrap()
+            self.dist.partial_cmp(&other.dist).unwrap().then_with(|| self.point.id().cmp(&other.point.id()))
         } else {
             panic!("got a NaN in a distance");
         }
@@ -96,9 +99,27 @@ pub struct LevelVec<T: Clone> {
 }
 
 impl<T: Clone> LevelVec<T> {
+    //每个邻居 16 个字节 id_level 和 dist 的小端字节 后面补 4 个 0
     pub fn to_vec(&self)-> Vec<u8> {
-        let ids: Vec<(u64, f32)> = self.value.iter().map(|v| (v.point.id_level, v.dist) ).collect();
-        super::vec_to_u8(ids)
+        let mut buf = Vec::with_capacity(self.value.len() * 16);
+        for v in &self.value {
+            buf.extend_from_slice(&v.point.id_level.to_le_bytes());
+            buf.extend_from_slice(&v.dist.to_le_bytes());
+            buf.extend_from_slice(&[0; 4]);
+        }
+        buf
+    }
+
+    pub(crate) fn bytes_contain(bytes: &[u8], id: u64)-> bool {
+        bytes.chunks_exact(16).any(|b| u64::from_le_bytes(b[..8].try_into().unwrap()) & ID_MASK == id)
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8])-> Self {
+        let mut neighbor = Self::default();
+        for b in bytes.chunks_exact(16) {
+            neighbor.push(OrderId::new(u64::from_le_bytes(b[..8].try_into().unwrap()), f32::from_le_bytes(b[8..12].try_into().unwrap())), None);
+        }
+        neighbor
     }
 }
 
@@ -136,6 +157,16 @@ impl<T: Clone> LevelVec<T> {
         } else { false }
     }
 
+    //id 的向量变了以后 修正所有指向它的边的距离 shrink 才会按照新的距离淘汰
+    pub(crate) fn set_dist(&mut self, id: u64, dist: f32)-> bool {
+        let mut changed = false;
+        for v in self.value.iter_mut().filter(|v| v.point.id() == id && v.dist != dist) {
+            v.dist = dist;
+            changed = true;
+        }
+        changed
+    }
+
     pub(crate) fn append(&mut self, other: &mut Vec<OrderId<T>>) {
         self.value.append(other);
     }
@@ -145,7 +176,7 @@ impl<T: Clone> LevelVec<T> {
     }
 
     fn first(&self, level: usize) -> Option<OrderId<T>> {
-        self.value.iter().find(|v| v.point.level() == level).map(|v| v.clone())
+        self.value.iter().find(|v| v.point.level() == level).cloned()
     }
 
     fn sort(&mut self) {
@@ -170,14 +201,12 @@ impl<T: Clone> LevelVec<T> {
             if v.point.level() == level {
                 if pos_value.is_none() {
                     pos_value = Some((1, pos, v.dist));
-                } else {
-                    pos_value.as_mut().map(|p| {
-                        if p.2 < v.dist {
-                            p.2 = v.dist;
-                            p.1 = pos;
-                        }
-                        p.0 += 1;
-                    });
+                } else if let Some(p) = pos_value.as_mut() {
+                    if p.2 < v.dist {
+                        p.2 = v.dist;
+                        p.1 = pos;
+                    }
+                    p.0 += 1;
                 }
             }
         });
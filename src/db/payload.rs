@@ -0,0 +1,50 @@
+//每个向量可以附带的 payload 类似 json 的结构 用 rmp-serde 持续化
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+pub type Payload = BTreeMap<String, Value>;
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self { Self::Bool(v) }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self { Self::Int(v) }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self { Self::Int(v as i64) }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self { Self::Float(v) }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self { Self::String(v.into()) }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self { Self::String(v) }
+}
+
+impl<V: Into<Value>> From<Vec<V>> for Value {
+    fn from(v: Vec<V>) -> Self { Self::Array(v.into_iter().map(|v| v.into()).collect()) }
+}
+
+//方便构造 payload 比如 payload([("title", "hello".into()), ("year", 2024.into())])
+pub fn payload<K: Into<String>, const N: usize>(fields: [(K, Value); N]) -> Payload {
+    fields.into_iter().map(|(k, v)| (k.into(), v)).collect()
+}
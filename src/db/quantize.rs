@@ -0,0 +1,61 @@
+//标量量化 每一维按照学习到的 min max 线性映射到 0..=255 内存里只保存一个字节的编码
+//计算距离时解码成 f32 超出范围的值会被截断 数据分布变化以后可以重新训练
+use super::element::{float_distance, Element};
+use super::Dist;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Quantizer {
+    min: Vec<f32>,
+    scale: Vec<f32>,                            //(max - min) / 255
+}
+
+impl Quantizer {
+    pub fn train<E: Element, I: Iterator<Item = Vec<E>>>(arrows: I) -> Option<Self> {
+        let mut range: Option<(Vec<f32>, Vec<f32>)> = None;
+        for arrow in arrows {
+            let (min, max) = range.get_or_insert_with(|| (vec![f32::MAX; arrow.len()], vec![f32::MIN; arrow.len()]));
+            for ((v, min), max) in arrow.iter().zip(min.iter_mut()).zip(max.iter_mut()) {
+                *min = min.min(v.to_f32());
+                *max = max.max(v.to_f32());
+            }
+        }
+        range.map(|(min, max)| {
+            let scale = min.iter().zip(&max).map(|(min, max)| (max - min) / 255.).collect();
+            Self { min, scale }
+        })
+    }
+
+    pub fn encode<E: Element>(&self, arrow: &[E]) -> Vec<u8> {
+        arrow.iter().zip(self.min.iter().zip(&self.scale)).map(|(v, (min, scale))| if *scale > 0. { ((v.to_f32() - min) / scale).round().clamp(0., 255.) as u8 } else { 0 }).collect()
+    }
+
+    pub fn decode<'a>(&'a self, codes: &'a [u8]) -> impl Iterator<Item = f32> + 'a {
+        codes.iter().zip(self.min.iter().zip(&self.scale)).map(|(c, (min, scale))| min + *c as f32 * scale)
+    }
+
+    pub(crate) fn distance<A: Iterator<Item = f32>, B: Iterator<Item = f32>>(dist: &Dist, va: A, vb: B) -> f32 {
+        match dist {
+            Dist::Custom(_) => dist.eval(&va.collect::<Vec<_>>(), &vb.collect::<Vec<_>>()),
+            dist => float_distance(dist, va, vb),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quantizer;
+
+    #[test]
+    fn test_quantizer() {
+        let q = Quantizer::train((0..100).map(|i| vec![i as f32 / 10., -1., 1000. - i as f32])).unwrap();
+        let codes = q.encode(&[5.0f32, -1., 950.]);
+        assert_eq!(codes[1], 0);
+        let decoded: Vec<f32> = q.decode(&codes).collect();
+        assert!((decoded[0] - 5.).abs() <= 9.9 / 255.);
+        assert_eq!(decoded[1], -1.);
+        assert!((decoded[2] - 950.).abs() <= 99. / 255.);
+        assert_eq!(q.encode(&[100f32, 0., 0.]), vec![255, 0, 0]);                //超出范围的值截断
+        assert!(Quantizer::train(std::iter::empty::<Vec<f32>>()).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+//每次查询可以单独设置的参数
+use super::filter::Filter;
+use super::payload::Payload;
+
+#[derive(Clone, Debug)]
+pub struct SearchParams {
+    pub(crate) k: usize,
+    pub(crate) ef: Option<usize>,               //没有设置时 使用集合的 ef
+    pub(crate) max_distance: Option<f32>,       //距离大于 max_distance 的结果不返回
+    pub(crate) filter: Option<Filter>,
+    pub(crate) offset: usize,                   //跳过前 offset 个结果
+    pub(crate) after: Option<(f32, u64)>,       //只返回 (dist, id) 大于这个游标的结果
+}
+
+impl SearchParams {
+    pub fn new(k: usize) -> Self {
+        Self { k, ef: None, max_distance: None, filter: None, offset: 0, after: None }
+    }
+    pub fn ef(mut self, ef: usize) -> Self {
+        self.ef = Some(ef);
+        self
+    }
+    pub fn max_distance(mut self, max_distance: f32) -> Self {
+        self.max_distance = Some(max_distance);
+        self
+    }
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+    //上一页最后一个结果的距离和 id
+    pub fn search_after(mut self, last_dist: f32, last_id: u64) -> Self {
+        self.after = Some((last_dist, last_id));
+        self
+    }
+}
+
+//搜索结果里 是否附带向量和 payload
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SearchOptions {
+    pub with_vector: bool,
+    pub with_payload: bool,
+}
+
+impl SearchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_vector(mut self) -> Self {
+        self.with_vector = true;
+        self
+    }
+    pub fn with_payload(mut self) -> Self {
+        self.with_payload = true;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hit<E = f32> {
+    pub id: u64,
+    pub dist: f32,
+    pub vector: Option<Vec<E>>,
+    pub payload: Option<Payload>,
+}
+
+//recommend 的策略
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    AverageVector,              //正例的平均向量 加上 正例平均 减去 负例平均 作为查询向量
+    BestScore,                  //每个正例分别搜索 按照到最近正例的距离排序 离负例更近的排在最后
+}
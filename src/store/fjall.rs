@@ -5,6 +5,10 @@ impl super::KVStore for FjallStore {
         Ok(value)
     }
 
+    fn try_get(&self, key: Bytes)-> Result<Option<Bytes>> {
+        Ok(self.tx.get(&key)?.map(|value| Bytes::copy_from_slice(value.as_ref())))
+    }
+
     fn set(&self, key: Bytes, value: Bytes)-> Result<()> {
         Ok(self.tx.insert(key.as_ref(), value.as_ref())?)
     }
@@ -19,12 +23,21 @@ impl super::KVStore for FjallStore {
             let val = f(old);
             if val.is_empty() { None }
             else { Some(val.as_ref().into()) }
-        }).unwrap().map(|old| Bytes::copy_from_slice(&old)).unwrap_or(Bytes::default())
+        }).unwrap().map(|old| Bytes::copy_from_slice(&old)).unwrap_or_default()
+    }
+
+    fn scan(&self, prefix: Bytes)-> impl Iterator<Item = Result<(Bytes, Bytes)>> {
+        self.tx.inner().prefix(prefix).map(|kv| kv.map(|(k, v)| (Bytes::copy_from_slice(&k), Bytes::copy_from_slice(&v))).map_err(|e| e.into()))
+    }
+
+    fn range(&self, start: Bound<Bytes>, end: Bound<Bytes>)-> impl Iterator<Item = Result<(Bytes, Bytes)>> {
+        self.tx.inner().range((start, end)).map(|kv| kv.map(|(k, v)| (Bytes::copy_from_slice(&k), Bytes::copy_from_slice(&v))).map_err(|e| e.into()))
     }
 }
 
 use fjall::{PartitionCreateOptions, TxKeyspace, TxPartition};
 use anyhow::{anyhow, Result};
+use std::ops::Bound;
 
 #[derive(Clone)]
 pub struct FjallStore {
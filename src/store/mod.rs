@@ -1,17 +1,21 @@
 //需要提供一个底层的 KV Store
 use anyhow::Result;
 use bytes::Bytes;
+use std::ops::Bound;
 use crate::db::{ID_BITS, ID_MASK};
 //需要定义一个
 pub trait KVStore {
     fn get(&self, key: Bytes)-> Result<Bytes>;
+    fn try_get(&self, key: Bytes)-> Result<Option<Bytes>>;      //key 不存在时返回 None 而不是错误
     fn set(&self, key: Bytes, value: Bytes)-> Result<()>;
     fn remove(&self, key: Bytes)-> Result<()>;
     fn update<F: Fn(Bytes)-> Bytes>(&self, key: Bytes, f: F)-> Bytes;
+    fn scan(&self, prefix: Bytes)-> impl Iterator<Item = Result<(Bytes, Bytes)>>;                   //前缀扫描
+    fn range(&self, start: Bound<Bytes>, end: Bound<Bytes>)-> impl Iterator<Item = Result<(Bytes, Bytes)>>;  //按 key 的字节顺序范围扫描
 }
 
 fn bytes_to_u64(buf: Bytes)-> u64 {
-    buf.as_ref().try_into().map(|buf| u64::from_le_bytes(buf) ).unwrap_or(0)
+    buf.as_ref().try_into().map(u64::from_le_bytes ).unwrap_or(0)
 }
 
 fn u64_to_bytes(value: u64)-> Bytes {
@@ -24,7 +28,7 @@ const ENTRY_KEY: Bytes = Bytes::from_static(b"__entry__");
 
 impl<T: KVStore> PersistID for T {
     fn size(&self)-> u64 {                           //获取总的 ID 数目 不精确包括了已删除的
-        self.get(ID_KEY).map(|buf| bytes_to_u64(buf) ).unwrap_or(0)
+        self.get(ID_KEY).map(bytes_to_u64 ).unwrap_or(0)
     }
 
     fn get_id(&self) -> u64 {                           //获取一个新的 ID
@@ -35,7 +39,7 @@ impl<T: KVStore> PersistID for T {
     }
 
     fn entry(&self)-> (usize, u64) {                 //获取入口点
-        let id_level = self.get(ENTRY_KEY).map(|buf| bytes_to_u64(buf) ).unwrap_or(0);
+        let id_level = self.get(ENTRY_KEY).map(bytes_to_u64 ).unwrap_or(0);
         ((id_level >> ID_BITS) as usize, id_level & ID_MASK)
     }
 
@@ -46,6 +50,10 @@ impl<T: KVStore> PersistID for T {
             else { u64_to_bytes(old_val) }
         });
     }
+
+    fn replace_entry(&self, level: usize, id: u64) {  //入口点被删除时 直接替换
+        let _ = self.set(ENTRY_KEY, u64_to_bytes(super::db::order_id::level_id(id, level)));
+    }
 }
 
 pub mod fjall;